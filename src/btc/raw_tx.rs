@@ -2,23 +2,21 @@ use super::utils::{get_all_addresses, list_unspent_for_addresss, BTC_TESTNET};
 use crate::btc::utils::{get_bitcoin_network, get_new_address, to_bitcoin_public_key};
 use crate::dto::btc::UtxoAggregator;
 use crate::dto::ecdsa::{MKPosAddressDto, MKPosDto, PrivateShare};
-use crate::ecdsa::sign;
+use crate::ecdsa::{sign_batch, SignItem};
 use crate::utilities::derive_new_key;
-use crate::utilities::err_handling::{error_to_c_string, ErrorFFIKind};
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind, TssError};
 use crate::utilities::ffi::ffi_utils::{
-    get_addresses_derivation_map_from_raw, get_client_shim_from_raw, get_private_share_from_raw,
-    get_str_from_c_char,
+    ffi_guard, get_addresses_derivation_map_from_raw, get_client_shim_from_raw,
+    get_private_share_from_raw, get_str_from_c_char,
 };
-use crate::utilities::requests::ClientShim;
+use crate::utilities::transport::Party1Transport;
 
-use anyhow::{anyhow, Result};
 use bitcoin::util::bip143::SigHashCache;
 use curv::arithmetic::traits::Converter; // Need for signing
 use curv::elliptic::curves::traits::ECPoint;
 use curv::BigInt;
 
 use std::collections::HashMap;
-use std::ffi::CString;
 use std::os::raw::c_char;
 
 use bitcoin::consensus::encode::serialize;
@@ -37,30 +35,33 @@ pub struct BtcRawTxFFIResp {
     pub change_address_payload: MKPosAddressDto,
 }
 
-pub fn create_raw_tx(
+pub fn create_raw_tx<C: Party1Transport>(
     to_address: &str,
     sent_amount: f64,
-    client_shim: &ClientShim,
+    client_shim: &C,
     last_derived_pos: u32,
     private_share: &PrivateShare,
     addresses_derivation_map: &HashMap<String, MKPosDto>,
-) -> Result<Option<BtcRawTxFFIResp>> {
+) -> Result<Option<BtcRawTxFFIResp>, TssError> {
     let selected = select_tx_in(last_derived_pos, private_share)?;
 
     /* Specify "vin" array aka Transaction Inputs */
-    let txs_in: Vec<TxIn> = selected
-        .clone()
-        .into_iter()
-        .map(|s| bitcoin::TxIn {
+    let mut txs_in: Vec<TxIn> = Vec::new();
+    for s in selected.iter() {
+        let tx_hash = match sha256d::Hash::from_hex(&s.tx_hash) {
+            Ok(h) => h,
+            Err(e) => return Err(TssError::Serialization(format!("utxo tx_hash: {}", e))),
+        };
+        txs_in.push(bitcoin::TxIn {
             previous_output: bitcoin::OutPoint {
-                txid: Txid::from_hash(sha256d::Hash::from_hex(&s.tx_hash).unwrap()),
+                txid: Txid::from_hash(tx_hash),
                 vout: s.tx_pos as u32,
             },
             script_sig: bitcoin::Script::default(),
             sequence: 0xFFFFFFFF,
             witness: Vec::default(),
-        })
-        .collect();
+        });
+    }
 
     /* Specify "vout" array aka Transaction Outputs */
     let relay_fees = 10_000; // Relay fees for miner
@@ -68,12 +69,7 @@ pub fn create_raw_tx(
 
     let (change_pos, change_mk) = derive_new_key(private_share, last_derived_pos);
 
-    let change_address = match get_new_address(private_share, last_derived_pos) {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(anyhow!("Error while get new btc address: {}", e));
-        }
-    };
+    let change_address = get_new_address(private_share, last_derived_pos)?;
 
     let change_address_payload = MKPosAddressDto {
         address: change_address.to_string(),
@@ -92,10 +88,16 @@ pub fn create_raw_tx(
     );
 
     if total_selected < (amount_satoshi + relay_fees) {
-        return Err(anyhow!("Not enough fund"));
+        return Err(TssError::InsufficientFunds {
+            needed: amount_satoshi + relay_fees,
+            available: total_selected,
+        });
     }
 
-    let to_btc_adress = bitcoin::Address::from_str(to_address)?;
+    let to_btc_adress = match bitcoin::Address::from_str(to_address) {
+        Ok(a) => a,
+        Err(e) => return Err(TssError::invalid_address(to_address, e)),
+    };
     let txs_out = vec![
         TxOut {
             value: amount_satoshi,
@@ -117,12 +119,14 @@ pub fn create_raw_tx(
     let mut signed_transaction = transaction.clone();
 
     /* Signing transaction */
+    let mut sign_items: Vec<SignItem> = Vec::new();
     for (i, txi) in selected.iter().enumerate().take(transaction.input.len()) {
         let address_derivation = match addresses_derivation_map.get(&txi.address) {
             Some(s) => s,
             None => {
-                return Err(anyhow!(
-                    "Error while get address from addresses_derivation_map"
+                return Err(TssError::invalid_address(
+                    &txi.address,
+                    "Error while get address from addresses_derivation_map",
                 ));
             }
         };
@@ -142,20 +146,27 @@ pub fn create_raw_tx(
             SigHashType::All,
         );
 
-        let signature = sign(
-            client_shim,
-            BigInt::from_hex(&hex::encode(&sig_hash[..])).unwrap(),
+        sign_items.push(SignItem {
+            message: BigInt::from_bytes(&sig_hash[..]),
             mk,
-            BigInt::from(0),
-            BigInt::from(address_derivation.pos),
-            &private_share.id,
-        )?;
+            x_pos: BigInt::from(0),
+            y_pos: BigInt::from(address_derivation.pos),
+        });
+    }
+
+    // All inputs in one signing session
+    let signatures = sign_batch(client_shim, &sign_items, &private_share.id)?;
 
-        let mut v = BigInt::to_bytes(&signature.r);
-        v.extend(BigInt::to_bytes(&signature.s));
+    for (i, (item, signature)) in sign_items.iter().zip(signatures.iter()).enumerate() {
+        let pk = item.mk.public.q.get_element();
+
+        let v = to_compact(&signature.r, &signature.s)?;
 
         // Serialize the (R,S) value of ECDSA Signature
-        let mut sig_vec = Signature::from_compact(&v[..])?.serialize_der().to_vec();
+        let mut sig_vec = match Signature::from_compact(&v[..]) {
+            Ok(s) => s.serialize_der().to_vec(),
+            Err(e) => return Err(TssError::verification("btc tx signature", e)),
+        };
         sig_vec.push(1);
 
         let pk_vec = pk.serialize().to_vec();
@@ -169,10 +180,26 @@ pub fn create_raw_tx(
 }
 
 // TODO: handle fees
+// r || s, each left-padded to 32 bytes; `BigInt::to_bytes` drops leading zeros
+fn to_compact(r: &BigInt, s: &BigInt) -> Result<[u8; 64], TssError> {
+    let mut compact = [0u8; 64];
+    for (half, n) in compact.chunks_mut(32).zip([r, s].iter()) {
+        let bytes = BigInt::to_bytes(n);
+        if bytes.len() > 32 {
+            return Err(TssError::verification(
+                "btc tx signature",
+                "signature scalar longer than 32 bytes",
+            ));
+        }
+        half[32 - bytes.len()..].copy_from_slice(&bytes);
+    }
+    Ok(compact)
+}
+
 pub fn select_tx_in(
     last_derived_pos: u32,
     private_share: &PrivateShare,
-) -> Result<Vec<UtxoAggregator>> {
+) -> Result<Vec<UtxoAggregator>, TssError> {
     // greedy selection
     let list_unspent: Vec<UtxoAggregator> = get_all_addresses(last_derived_pos, private_share)?
         .into_iter()
@@ -201,64 +228,68 @@ pub extern "C" fn get_raw_btc_tx(
     c_private_share_json: *const c_char,
     c_addresses_derivation_map: *const c_char,
 ) -> *mut c_char {
-    let to_address = match get_str_from_c_char(c_to_address, "to_address") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+    ffi_guard("get_raw_btc_tx", || {
+        let to_address = match get_str_from_c_char(c_to_address, "to_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let private_share = match get_private_share_from_raw(c_private_share_json) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let addresses_derivation_map =
-        match get_addresses_derivation_map_from_raw(c_addresses_derivation_map) {
+        let private_share = match get_private_share_from_raw(c_private_share_json) {
             Ok(s) => s,
             Err(e) => return error_to_c_string(e),
         };
 
-    let raw_tx_opt = match create_raw_tx(
-        &to_address,
-        c_amount_btc,
-        &client_shim,
-        c_last_derived_pos,
-        &private_share,
-        &addresses_derivation_map,
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "raw_tx".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let addresses_derivation_map =
+            match get_addresses_derivation_map_from_raw(c_addresses_derivation_map) {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        let raw_tx_opt = match create_raw_tx(
+            &to_address,
+            c_amount_btc,
+            &client_shim,
+            c_last_derived_pos,
+            &private_share,
+            &addresses_derivation_map,
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "raw_tx".to_owned(),
+                    e,
+                })
+            }
+        };
 
-    let raw_tx = match raw_tx_opt {
-        Some(tx) => tx,
-        None => return std::ptr::null_mut(),
-    };
+        ok_to_c_string(&raw_tx_opt, "raw_tx")
+    })
+}
 
-    let raw_tx_json = match serde_json::to_string(&raw_tx) {
-        Ok(tx_resp) => tx_resp,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "raw_tx".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+#[cfg(test)]
+mod tests {
+    use bitcoin::secp256k1::Signature;
+    use curv::BigInt;
+
+    use super::to_compact;
+
+    #[test]
+    fn test_to_compact_pads_short_scalars() {
+        // r with a leading zero byte, as about one signature in 256 has
+        let r = BigInt::from(0x1234_5678);
+        let s = BigInt::from(1) << 250;
+        let compact = to_compact(&r, &s).unwrap();
+        assert_eq!(&compact[28..32], &[0x12, 0x34, 0x56, 0x78]);
+        assert!(compact[..28].iter().all(|b| *b == 0));
+
+        let signature = Signature::from_compact(&compact).unwrap();
+        assert_eq!(&signature.serialize_compact()[..], &compact[..]);
 
-    match CString::new(raw_tx_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "raw_tx".to_owned(),
-            e: e.to_string(),
-        }),
+        assert!(to_compact(&(BigInt::from(1) << 256), &s).is_err());
     }
 }
@@ -1,4 +1,3 @@
-use anyhow::{anyhow, Result};
 use bitcoin::{self, Network};
 use curv::elliptic::curves::secp256_k1::PK;
 use curv::elliptic::curves::traits::ECPoint;
@@ -8,13 +7,16 @@ use kms::ecdsa::two_party::MasterKey2;
 use crate::dto::btc::{BlockCypherAddress, UtxoAggregator};
 use crate::dto::ecdsa::PrivateShare;
 use crate::utilities::derive_new_key;
+use crate::utilities::err_handling::{RequestError, TssError};
 
 pub const BTC_TESTNET: &str = "testnet";
 pub const BLOCK_CYPHER_HOST: &str = "https://api.blockcypher.com/v1/btc/test3"; // TODO: Centralize the config constants
 
-pub fn list_unspent_for_addresss(address: String) -> Result<Vec<UtxoAggregator>> {
+pub fn list_unspent_for_addresss(address: String) -> Result<Vec<UtxoAggregator>, TssError> {
     let unspent_tx_url = BLOCK_CYPHER_HOST.to_owned() + "/addrs/" + &address + "?unspentOnly=true";
-    let res = reqwest::blocking::get(unspent_tx_url)?.text()?;
+    let res = reqwest::blocking::get(&unspent_tx_url)
+        .and_then(|r| r.text())
+        .map_err(|e| RequestError::from_reqwest(&unspent_tx_url, e))?;
     let address_balance_with_tx_refs: BlockCypherAddress = serde_json::from_str(res.as_str())?;
     if let Some(tx_refs) = address_balance_with_tx_refs.txrefs {
         Ok(tx_refs
@@ -35,7 +37,7 @@ pub fn list_unspent_for_addresss(address: String) -> Result<Vec<UtxoAggregator>>
 pub fn get_all_addresses(
     last_derived_pos: u32,
     private_share: &PrivateShare,
-) -> Result<Vec<bitcoin::Address>> {
+) -> Result<Vec<bitcoin::Address>, TssError> {
     let init = 0;
     let last_pos = last_derived_pos;
 
@@ -57,16 +59,19 @@ pub fn get_all_addresses(
 pub fn get_new_address(
     private_share: &PrivateShare,
     last_derived_pos: u32,
-) -> Result<bitcoin::Address> {
+) -> Result<bitcoin::Address, TssError> {
     let (_pos, mk) = derive_new_key(private_share, last_derived_pos);
     to_bitcoin_address(BTC_TESTNET, &mk)
 }
 
-pub fn to_bitcoin_address(network: &str, mk: &MasterKey2) -> Result<bitcoin::Address> {
+pub fn to_bitcoin_address(network: &str, mk: &MasterKey2) -> Result<bitcoin::Address, TssError> {
     let pk = mk.public.q.get_element();
     match bitcoin::Address::p2wpkh(&to_bitcoin_public_key(pk), get_bitcoin_network(network)?) {
         Ok(address) => Ok(address),
-        Err(e) => Err(anyhow!("Error while creating bitcoin address: {}", e)),
+        Err(e) => Err(TssError::invalid_address(
+            &hex::encode(&pk.serialize()[..]),
+            format!("Error while creating bitcoin address: {}", e),
+        )),
     }
 }
 
@@ -77,7 +82,12 @@ pub fn to_bitcoin_public_key(pk: PK) -> bitcoin::util::key::PublicKey {
     }
 }
 
-pub fn get_bitcoin_network(nw: &str) -> Result<Network> {
-    let btc_nw = nw.to_owned().parse::<Network>()?;
-    Ok(btc_nw)
+pub fn get_bitcoin_network(nw: &str) -> Result<Network, TssError> {
+    match nw.to_owned().parse::<Network>() {
+        Ok(btc_nw) => Ok(btc_nw),
+        Err(e) => Err(TssError::invalid_address(
+            nw,
+            format!("unknown network: {}", e),
+        )),
+    }
 }
@@ -0,0 +1,78 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use centipede::juggling::proof_system::{Helgamalsegmented, Proof};
+use curv::BigInt;
+use kms::ecdsa::two_party::Party2Public;
+
+use crate::utilities::err_handling::TssError;
+
+pub const CLIENT_BACKUP_VERSION: u32 = 1;
+
+/// Client share encrypted to the escrow key, with the proof that it is P2's share.
+///
+/// Backups written before versioning were a bare `(encryptions, proof, public, chain_code,
+/// id)` tuple; `from_json` migrates them, leaving the fields they did not record as `None`.
+#[derive(Serialize, Deserialize)]
+pub struct ClientBackup {
+    pub version: u32,
+    pub id: String,
+    pub coin_type: Option<String>,
+    pub network: Option<String>,
+    pub segment_size: usize,
+    pub num_segments: usize,
+    /// `escrow::fingerprint` of the escrow public key the share is encrypted to.
+    pub escrow_key_fingerprint: Option<String>,
+    /// Unix time in seconds.
+    pub created_at: Option<u64>,
+    pub encryptions: Helgamalsegmented,
+    pub proof: Proof,
+    pub public: Party2Public,
+    pub chain_code: BigInt,
+}
+
+type LegacyClientBackup = (Helgamalsegmented, Proof, Party2Public, BigInt, String);
+
+impl ClientBackup {
+    pub fn from_json(data: &str) -> Result<ClientBackup, TssError> {
+        let value: serde_json::Value = serde_json::from_str(data)?;
+        if value.is_array() {
+            let (encryptions, proof, public, chain_code, id): LegacyClientBackup =
+                serde_json::from_value(value)?;
+            return Ok(ClientBackup {
+                version: CLIENT_BACKUP_VERSION,
+                id,
+                coin_type: None,
+                network: None,
+                // Fixed on client and server before versioning
+                segment_size: 8,
+                num_segments: 32,
+                escrow_key_fingerprint: None,
+                created_at: None,
+                encryptions,
+                proof,
+                public,
+                chain_code,
+            });
+        }
+
+        let backup: ClientBackup = serde_json::from_value(value)?;
+        if backup.version > CLIENT_BACKUP_VERSION {
+            return Err(TssError::Serialization(format!(
+                "client backup version {} is newer than supported version {}",
+                backup.version, CLIENT_BACKUP_VERSION
+            )));
+        }
+        Ok(backup)
+    }
+
+    pub fn to_json(&self) -> Result<String, TssError> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+pub(crate) fn now_unix_secs() -> Option<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .ok()
+        .map(|d| d.as_secs())
+}
@@ -1,7 +1,13 @@
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
+
 use curv::BigInt;
 use kms::ecdsa::two_party::MasterKey2;
 use kms::ecdsa::two_party::*;
 
+use crate::utilities::secret::{wipe_master_key, REDACTED};
+
 #[derive(Serialize, Deserialize)]
 pub struct PrivateShare {
     pub id: String,
@@ -18,6 +24,21 @@ impl PrivateShare {
     }
 }
 
+impl Drop for PrivateShare {
+    fn drop(&mut self) {
+        wipe_master_key(&mut self.master_key);
+    }
+}
+
+impl fmt::Debug for PrivateShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrivateShare")
+            .field("id", &self.id)
+            .field("master_key", &REDACTED)
+            .finish()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SignSecondMsgRequest {
     pub message: BigInt,
@@ -32,9 +53,58 @@ pub struct MKPosDto {
     pub mk: MasterKey2,
 }
 
+// Child keys carry the same secret share as the master key
+impl Drop for MKPosDto {
+    fn drop(&mut self) {
+        wipe_master_key(&mut self.mk);
+    }
+}
+
+impl fmt::Debug for MKPosDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MKPosDto")
+            .field("pos", &self.pos)
+            .field("mk", &REDACTED)
+            .finish()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MKPosAddressDto {
     pub address: String,
     pub pos: u32,
     pub mk: MasterKey2,
 }
+
+impl MKPosAddressDto {
+    /// Split into address, position and key; the key is moved, not copied.
+    pub fn into_parts(self) -> (String, u32, MasterKey2) {
+        let mut this = ManuallyDrop::new(self);
+        let address = mem::take(&mut this.address);
+        // `this` is never dropped, so the key has a single owner again
+        let mk = unsafe { ptr::read(&this.mk) };
+        (address, this.pos, mk)
+    }
+}
+
+impl Drop for MKPosAddressDto {
+    fn drop(&mut self) {
+        wipe_master_key(&mut self.mk);
+    }
+}
+
+impl fmt::Debug for MKPosAddressDto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MKPosAddressDto")
+            .field("address", &self.address)
+            .field("pos", &self.pos)
+            .field("mk", &REDACTED)
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PosAddressDto {
+    pub address: String,
+    pub pos: u32,
+}
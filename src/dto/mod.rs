@@ -1,3 +1,4 @@
+pub mod backup;
 pub mod btc;
 pub mod ecdsa;
 pub mod eth;
@@ -0,0 +1,74 @@
+use floating_duration::TimeFormat;
+use std::time::Instant;
+
+use super::keygen::{KeygenState, KG_PATH_PRE};
+use crate::dto::ecdsa::PrivateShare;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::transport::AsyncParty1Transport;
+
+// This is the same as get_private_share, but it is async
+pub async fn a_get_private_share<C: AsyncParty1Transport + Sync>(
+    client_shim: &C,
+) -> Result<PrivateShare, TssError> {
+    let start = Instant::now();
+    let mut state = KeygenState::new();
+
+    if let Err(e) = state.a_run(client_shim).await {
+        let _ = state.a_abort(client_shim).await;
+        return Err(e);
+    }
+
+    let private_share = state.into_private_share()?;
+    println!(
+        "(id: {}) Took: {}",
+        private_share.id,
+        TimeFormat(start.elapsed())
+    );
+
+    Ok(private_share)
+}
+
+// Async driving of the keygen rounds, see `KeygenState::step`
+impl KeygenState {
+    pub async fn a_step<C: AsyncParty1Transport + Sync>(
+        &mut self,
+        client_shim: &C,
+    ) -> Result<(), TssError> {
+        let (path, body) = match self.next_request()? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let response: serde_json::Value = match client_shim.a_postb(&path, body).await? {
+            Some(s) => s,
+            None => return Err(TssError::protocol(&path, "empty response")),
+        };
+
+        self.apply_response(response)
+    }
+
+    pub async fn a_run<C: AsyncParty1Transport + Sync>(
+        &mut self,
+        client_shim: &C,
+    ) -> Result<(), TssError> {
+        while !self.is_done() {
+            self.a_step(client_shim).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn a_abort<C: AsyncParty1Transport + Sync>(
+        self,
+        client_shim: &C,
+    ) -> Result<(), TssError> {
+        if self.is_done() {
+            return Ok(());
+        }
+        if let Some(id) = self.id() {
+            client_shim
+                .a_post::<serde_json::Value>(&format!("{}/{}/abort", KG_PATH_PRE, id))
+                .await?;
+        }
+        Ok(())
+    }
+}
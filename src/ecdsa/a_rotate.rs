@@ -0,0 +1,85 @@
+use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use curv::elliptic::curves::secp256_k1::GE;
+use kms::ecdsa::two_party::*;
+use kms::rotation::two_party::party2::Rotation2;
+
+use super::rotate::{
+    confirm_result, rotate_master_key, rotation_result, RotationError, ROT_PATH_PRE,
+};
+use crate::dto::ecdsa::PrivateShare;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::transport::AsyncParty1Transport;
+
+// This is the same as rotate_private_share, but it is async
+pub async fn a_rotate_private_share<C: AsyncParty1Transport + Sync>(
+    private_share: PrivateShare,
+    client_shim: &C,
+) -> Result<PrivateShare, RotationError> {
+    let rotated_private_share = a_begin_rotation(&private_share, client_shim).await?;
+    let confirmed = a_confirm_rotation(client_shim, &rotated_private_share).await;
+    rotation_result(confirmed, rotated_private_share)
+}
+
+// This is the same as begin_rotation, but it is async
+pub async fn a_begin_rotation<C: AsyncParty1Transport + Sync>(
+    private_share: &PrivateShare,
+    client_shim: &C,
+) -> Result<PrivateShare, TssError> {
+    let id = &private_share.id;
+    let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage<GE> =
+        match client_shim
+            .a_post(&format!("{}/{}/first", ROT_PATH_PRE, id))
+            .await?
+        {
+            Some(s) => s,
+            None => {
+                return Err(TssError::protocol(
+                    "coin flip p1 first msg",
+                    "empty response",
+                ))
+            }
+        };
+
+    let coin_flip_party2_first_message =
+        Rotation2::key_rotate_first_message(&coin_flip_party1_first_message);
+
+    let body = &coin_flip_party2_first_message;
+
+    let (coin_flip_party1_second_message, rotation_party1_first_message): (
+        coin_flip_optimal_rounds::Party1SecondMessage<GE>,
+        party1::RotationParty1Message1,
+    ) = match client_shim
+        .a_postb(&format!("{}/{}/second", ROT_PATH_PRE, id), body)
+        .await?
+    {
+        Some(s) => s,
+        None => {
+            return Err(TssError::protocol(
+                "coin flip p1 second msg",
+                "empty response",
+            ))
+        }
+    };
+
+    rotate_master_key(
+        private_share,
+        &coin_flip_party1_first_message,
+        &coin_flip_party2_first_message,
+        &coin_flip_party1_second_message,
+        &rotation_party1_first_message,
+    )
+}
+
+// This is the same as confirm_rotation, but it is async
+pub async fn a_confirm_rotation<C: AsyncParty1Transport + Sync>(
+    client_shim: &C,
+    rotated_private_share: &PrivateShare,
+) -> Result<bool, TssError> {
+    let confirmed = client_shim
+        .a_postb_idempotent::<_, serde_json::Value>(
+            &format!("{}/{}/confirm", ROT_PATH_PRE, rotated_private_share.id),
+            &rotated_private_share.master_key.public.p2,
+        )
+        .await;
+    confirm_result(confirmed.map(|_| ()))
+}
@@ -1,75 +1,121 @@
-use crate::utilities::a_requests;
-use crate::utilities::a_requests::AsyncClientShim;
+use curv::BigInt;
+use kms::ecdsa::two_party::MasterKey2;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_two;
+
+use super::sign::{
+    handle_batch_first_messages, handle_batch_signatures, SignItem, SignState, SIGN_PATH_PRE,
+};
 use crate::dto::ecdsa::SignSecondMsgRequest;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::transport::AsyncParty1Transport;
 
-// This is the same as sign, but it is async, for testing purposes
-pub async fn a_sign(
-    client_shim: &AsyncClientShim,
+// This is the same as sign, but it is async
+pub async fn a_sign<C: AsyncParty1Transport + Sync>(
+    client_shim: &C,
     message: BigInt,
     mk: &MasterKey2,
     x_pos: BigInt,
     y_pos: BigInt,
     id: &str,
-) -> Result<party_one::SignatureRecid> {
-    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
-        MasterKey2::sign_first_message();
+) -> Result<party_one::SignatureRecid, TssError> {
+    let mut state = SignState::new(message, x_pos, y_pos);
 
-    let request: party_two::EphKeyGenFirstMsg = eph_key_gen_first_message_party_two;
-    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
-        match a_requests::a_postb(client_shim, &format!("/ecdsa/sign/{}/first", id), &request)
-            .await?
-        {
-            Some(s) => s,
-            None => return Err(anyhow!("party1 sign first message request failed")),
-        };
+    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg = match client_shim
+        .a_postb(
+            &format!("{}/{}/first", SIGN_PATH_PRE, id),
+            state.first_message(),
+        )
+        .await?
+    {
+        Some(s) => s,
+        None => return Err(TssError::protocol("sign first message", "empty response")),
+    };
 
-    let party_two_sign_message = mk.sign_second_message(
-        &eph_ec_key_pair_party2,
-        eph_comm_witness,
-        &sign_party_one_first_message,
-        &message,
-    );
+    let request = state.handle_party_one_first_message(mk, &sign_party_one_first_message)?;
 
-    let signature = match a_get_signature(
-        client_shim,
-        message,
-        party_two_sign_message,
-        x_pos,
-        y_pos,
-        id,
-    )
-    .await
+    let signature: party_one::SignatureRecid = match client_shim
+        .a_postb(&format!("{}/{}/second", SIGN_PATH_PRE, id), request)
+        .await?
     {
-        Ok(s) => s,
-        Err(e) => return Err(anyhow!("ecdsa::get_signature failed failed: {}", e)),
+        Some(s) => s,
+        None => return Err(TssError::protocol("sign second message", "empty response")),
     };
 
-    Ok(signature)
+    state.handle_signature(mk, signature)?;
+    state.into_signature()
 }
 
-
-async fn a_get_signature(
-    client_shim: &AsyncClientShim,
-    message: BigInt,
-    party_two_sign_message: party2::SignMessage,
-    x_pos_child_key: BigInt,
-    y_pos_child_key: BigInt,
+// This is the same as sign_batch, but it is async
+pub async fn a_sign_batch<C: AsyncParty1Transport + Sync>(
+    client_shim: &C,
+    items: &[SignItem<'_>],
     id: &str,
-) -> Result<party_one::SignatureRecid> {
-    let request: SignSecondMsgRequest = SignSecondMsgRequest {
-        message,
-        party_two_sign_message,
-        x_pos_child_key,
-        y_pos_child_key,
+) -> Result<Vec<party_one::SignatureRecid>, TssError> {
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut states: Vec<SignState> = items
+        .iter()
+        .map(|item| SignState::new(item.message.clone(), item.x_pos.clone(), item.y_pos.clone()))
+        .collect();
+
+    let first_messages: Vec<&party_two::EphKeyGenFirstMsg> =
+        states.iter().map(|s| s.first_message()).collect();
+    let party_one_first_messages: Vec<party_one::EphKeyGenFirstMsg> = match client_shim
+        .a_postb(
+            &format!("{}/{}/batch/first", SIGN_PATH_PRE, id),
+            &first_messages,
+        )
+        .await
+    {
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            return Err(TssError::protocol(
+                "sign batch first message",
+                "empty response",
+            ))
+        }
+        // Server without batch rounds: nothing is open yet, sign input by input
+        Err(e) if e.is_unsupported() => {
+            let mut signatures = Vec::with_capacity(items.len());
+            for item in items {
+                signatures.push(
+                    a_sign(
+                        client_shim,
+                        item.message.clone(),
+                        item.mk,
+                        item.x_pos.clone(),
+                        item.y_pos.clone(),
+                        id,
+                    )
+                    .await?,
+                );
+            }
+            return Ok(signatures);
+        }
+        Err(e) => return Err(e.into()),
     };
 
-    let signature: party_one::SignatureRecid =
-        match a_requests::a_postb(client_shim, &format!("/ecdsa/sign/{}/second", id), &request)
-            .await?
-        {
-            Some(s) => s,
-            None => return Err(anyhow!("party1 sign second message request failed",)),
-        };
+    handle_batch_first_messages(&mut states, items, &party_one_first_messages)?;
+    let second_messages: Vec<&SignSecondMsgRequest> =
+        states.iter().filter_map(|s| s.second_message()).collect();
+    let signatures: Vec<party_one::SignatureRecid> = match client_shim
+        .a_postb(
+            &format!("{}/{}/batch/second", SIGN_PATH_PRE, id),
+            &second_messages,
+        )
+        .await?
+    {
+        Some(s) => s,
+        None => {
+            return Err(TssError::protocol(
+                "sign batch second message",
+                "empty response",
+            ))
+        }
+    };
 
-    Ok(signature)
+    handle_batch_signatures(states, items, signatures)
 }
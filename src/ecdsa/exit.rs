@@ -0,0 +1,270 @@
+// Emergency exit: rebuild the full private key of a child key from the client share and
+// P1's escrowed backup, without P1. Only for moving funds out when the service is gone;
+// the exported key defeats the two-party setup.
+
+use std::fmt;
+
+use aes::Aes128;
+use centipede::juggling::proof_system::Helgamalsegmented;
+use centipede::juggling::segmentation::Msegmentation;
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use curv::arithmetic::{Converter, Modulo};
+use curv::elliptic::curves::secp256_k1::{FE, GE};
+use curv::elliptic::curves::traits::{ECPoint, ECScalar};
+use curv::BigInt;
+use kms::ecdsa::two_party::{MasterKey1, MasterKey2};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::Serialize;
+use web3::signing::keccak256;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::btc::utils::{get_bitcoin_network, to_bitcoin_public_key};
+use crate::dto::ecdsa::PrivateShare;
+use crate::escrow;
+use crate::eth::utils::public_key_to_eth_address;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::secret::REDACTED;
+
+type Aes128Ctr = ctr::Ctr128BE<Aes128>;
+
+// scrypt cost of keystore files, as written by geth
+const KEYSTORE_SCRYPT_LOG_N: u8 = 18;
+const KEYSTORE_SCRYPT_R: u32 = 8;
+const KEYSTORE_SCRYPT_P: u32 = 1;
+
+/// Full private key of one child key, i.e. `x1 * x2` of the derived shares.
+pub struct FullPrivateKey {
+    secret: FE,
+    pub public: GE,
+}
+
+/// P1's master key, rebuilt from P1's backup `p1_encryptions` (its share encrypted to the
+/// escrow key) and the escrow secret `escrow_key`.
+pub fn recover_party_one_master_key(
+    master_key: &MasterKey2,
+    p1_encryptions: &Helgamalsegmented,
+    escrow_key: &FE,
+) -> Result<MasterKey1, TssError> {
+    let g: GE = ECPoint::generator();
+    let x1 = Msegmentation::decrypt(p1_encryptions, &g, escrow_key, &escrow::SEGMENT_SIZE)
+        .map_err(|e| TssError::verification("decrypt party one backup", format!("{:?}", e)))?;
+    if g * x1 != master_key.public.p1 {
+        return Err(TssError::verification(
+            "decrypt party one backup",
+            "decrypted share does not match P1's public share",
+        ));
+    }
+    Ok(master_key.counter_master_key_from_recovered_secret(x1))
+}
+
+/// Full private key of the child at (`x_pos`, `y_pos`) of the client share, with P1's
+/// share taken from its escrowed backup.
+pub fn recover_full_private_key(
+    private_share: &PrivateShare,
+    p1_encryptions: &Helgamalsegmented,
+    escrow_key: &FE,
+    x_pos: BigInt,
+    y_pos: BigInt,
+) -> Result<FullPrivateKey, TssError> {
+    let mk1 = recover_party_one_master_key(&private_share.master_key, p1_encryptions, escrow_key)?;
+    let child_mk1 = mk1.get_child(vec![x_pos.clone(), y_pos.clone()]);
+    let child_mk2 = private_share.master_key.get_child(vec![x_pos, y_pos]);
+
+    let mut x1 = private_scalar(&child_mk1.private, "x1")?;
+    let mut x2 = private_scalar(&child_mk2.private, "x2")?;
+    let mut s = BigInt::mod_mul(&x1, &x2, &FE::q());
+    let secret: FE = ECScalar::from(&s);
+    for v in [&mut x1, &mut x2, &mut s] {
+        v.zeroize();
+    }
+
+    let full_key = FullPrivateKey {
+        secret,
+        public: child_mk2.public.q,
+    };
+    let g: GE = ECPoint::generator();
+    if g * full_key.secret != full_key.public {
+        return Err(TssError::verification(
+            "recover full private key",
+            "key does not match the child public key",
+        ));
+    }
+    Ok(full_key)
+}
+
+// The private shares of kms / multi-party-ecdsa are only reachable through serde
+fn private_scalar<T: Serialize>(private: &T, field: &str) -> Result<BigInt, TssError> {
+    let mut value = serde_json::to_value(private)?;
+    let scalar = match value[field].as_str() {
+        Some(hex) => BigInt::from_hex(hex).map_err(|e| {
+            TssError::Serialization(format!("invalid private share {}: {}", field, e))
+        }),
+        None => Err(TssError::Serialization(format!(
+            "private share has no {}",
+            field
+        ))),
+    };
+    if let serde_json::Value::String(s) = &mut value[field] {
+        s.zeroize();
+    }
+    scalar
+}
+
+impl FullPrivateKey {
+    /// 32 byte big endian hex, without prefix.
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        let mut bytes = self.secret_bytes();
+        let hex = Zeroizing::new(hex::encode(&bytes[..]));
+        bytes.zeroize();
+        hex
+    }
+
+    /// Wallet import format for a compressed key on `network`.
+    pub fn to_wif(&self, network: &str) -> Result<Zeroizing<String>, TssError> {
+        let private_key = bitcoin::PrivateKey {
+            compressed: true,
+            network: get_bitcoin_network(network)?,
+            key: self.secret.get_element(),
+        };
+        Ok(Zeroizing::new(private_key.to_wif()))
+    }
+
+    /// Ethereum keystore (version 3, scrypt and AES-128-CTR) encrypted under `passphrase`.
+    pub fn to_keystore_v3(&self, passphrase: &str) -> Result<String, TssError> {
+        self.keystore_v3(passphrase, KEYSTORE_SCRYPT_LOG_N)
+    }
+
+    fn keystore_v3(&self, passphrase: &str, log_n: u8) -> Result<String, TssError> {
+        let mut salt = [0u8; 32];
+        let mut iv = [0u8; 16];
+        let mut id = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut iv);
+        OsRng.fill_bytes(&mut id);
+
+        let params = scrypt::Params::new(log_n, KEYSTORE_SCRYPT_R, KEYSTORE_SCRYPT_P)
+            .map_err(|e| TssError::Storage(format!("invalid scrypt parameters: {}", e)))?;
+        let mut derived_key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut derived_key[..])
+            .map_err(|e| TssError::Storage(format!("key derivation failed: {}", e)))?;
+
+        let mut ciphertext = self.secret_bytes();
+        let mut cipher = Aes128Ctr::new_from_slices(&derived_key[..16], &iv)
+            .map_err(|e| TssError::Storage(format!("invalid cipher parameters: {}", e)))?;
+        cipher.apply_keystream(&mut ciphertext);
+
+        let mut mac_data = derived_key[16..].to_vec();
+        mac_data.extend_from_slice(&ciphertext);
+        let mac = keccak256(&mac_data);
+        mac_data.zeroize();
+
+        // Random (version 4) UUID
+        id[6] = (id[6] & 0x0f) | 0x40;
+        id[8] = (id[8] & 0x3f) | 0x80;
+        let id = hex::encode(id);
+
+        let keystore = serde_json::json!({
+            "version": 3,
+            "id": format!("{}-{}-{}-{}-{}", &id[..8], &id[8..12], &id[12..16], &id[16..20], &id[20..]),
+            "address": hex::encode(self.eth_address()),
+            "crypto": {
+                "cipher": "aes-128-ctr",
+                "cipherparams": { "iv": hex::encode(iv) },
+                "ciphertext": hex::encode(ciphertext),
+                "kdf": "scrypt",
+                "kdfparams": {
+                    "dklen": 32,
+                    "n": 1u64 << log_n,
+                    "r": KEYSTORE_SCRYPT_R,
+                    "p": KEYSTORE_SCRYPT_P,
+                    "salt": hex::encode(salt),
+                },
+                "mac": hex::encode(mac),
+            },
+        });
+        Ok(serde_json::to_string_pretty(&keystore)?)
+    }
+
+    pub fn btc_address(&self, network: &str) -> Result<bitcoin::Address, TssError> {
+        let pk = self.public.get_element();
+        bitcoin::Address::p2wpkh(&to_bitcoin_public_key(pk), get_bitcoin_network(network)?)
+            .map_err(|e| TssError::invalid_address(&hex::encode(&pk.serialize()[..]), e))
+    }
+
+    pub fn eth_address(&self) -> web3::types::Address {
+        public_key_to_eth_address(&self.public)
+    }
+
+    fn secret_bytes(&self) -> [u8; 32] {
+        let mut s = self.secret.to_big_int();
+        let mut bytes = [0u8; 32];
+        let mut be = BigInt::to_bytes(&s);
+        bytes[32 - be.len()..].copy_from_slice(&be);
+        be.zeroize();
+        s.zeroize();
+        bytes
+    }
+}
+
+impl Drop for FullPrivateKey {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl fmt::Debug for FullPrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FullPrivateKey")
+            .field("secret", &REDACTED)
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ctr::cipher::{KeyIvInit, StreamCipher};
+    use curv::elliptic::curves::secp256_k1::{FE, GE};
+    use curv::elliptic::curves::traits::{ECPoint, ECScalar};
+    use web3::signing::keccak256;
+
+    use super::{Aes128Ctr, FullPrivateKey};
+
+    #[test]
+    fn test_keystore_v3_decrypts() {
+        let secret: FE = ECScalar::new_random();
+        let g: GE = ECPoint::generator();
+        let key = FullPrivateKey {
+            secret,
+            public: g * secret,
+        };
+
+        // Light scrypt cost, the format is the same
+        let keystore: serde_json::Value =
+            serde_json::from_str(&key.keystore_v3("exit pass", 10).unwrap()).unwrap();
+        let crypto = &keystore["crypto"];
+        assert_eq!(keystore["version"], 3);
+        assert_eq!(keystore["address"], hex::encode(key.eth_address()));
+        assert_eq!(crypto["kdfparams"]["n"], 1024);
+
+        let salt = hex::decode(crypto["kdfparams"]["salt"].as_str().unwrap()).unwrap();
+        let iv = hex::decode(crypto["cipherparams"]["iv"].as_str().unwrap()).unwrap();
+        let mut data = hex::decode(crypto["ciphertext"].as_str().unwrap()).unwrap();
+        let params = scrypt::Params::new(10, 8, 1).unwrap();
+        let mut derived_key = [0u8; 32];
+        scrypt::scrypt(b"exit pass", &salt, &params, &mut derived_key).unwrap();
+
+        let mut mac_data = derived_key[16..].to_vec();
+        mac_data.extend_from_slice(&data);
+        assert_eq!(
+            hex::encode(keccak256(&mac_data)),
+            crypto["mac"].as_str().unwrap()
+        );
+
+        Aes128Ctr::new_from_slices(&derived_key[..16], &iv)
+            .unwrap()
+            .apply_keystream(&mut data);
+        assert_eq!(hex::encode(data), *key.to_hex());
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::{anyhow, Result};
 use floating_duration::TimeFormat;
 use serde_json;
 use std::time::Instant;
@@ -12,79 +11,330 @@ use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::*;
 use zk_paillier::zkproofs::SALT_STRING;
 
 use crate::dto::ecdsa::PrivateShare;
-use crate::utilities::err_handling::{error_to_c_string, ErrorFFIKind};
-use crate::utilities::ffi::ffi_utils::get_client_shim_from_raw;
-use crate::utilities::requests::ClientShim;
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind, TssError};
+use crate::utilities::ffi::ffi_utils::{ffi_guard, get_client_shim_from_raw};
+use crate::utilities::transport::Party1Transport;
 
-use super::super::utilities::requests;
-
-use std::ffi::CString;
 use std::os::raw::c_char;
 
-const KG_PATH_PRE: &str = "ecdsa/keygen";
+pub(crate) const KG_PATH_PRE: &str = "ecdsa/keygen";
 
-pub fn get_private_share(client_shim: &ClientShim) -> Result<PrivateShare> {
+pub fn get_private_share<C: Party1Transport>(client_shim: &C) -> Result<PrivateShare, TssError> {
     let start = Instant::now();
-    // Receive ECDH key exchange message from P1
-    let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
-        match requests::post(client_shim, &format!("{}/first", KG_PATH_PRE))? {
-            Some(s) => s,
-            None => return Err(anyhow!("keygen first message request failed")),
+    let mut state = KeygenState::new();
+
+    if let Err(e) = state.run(client_shim) {
+        // Do not leave a half-finished session behind on the server
+        let _ = state.abort(client_shim);
+        return Err(e);
+    }
+
+    let private_share = state.into_private_share()?;
+    println!(
+        "(id: {}) Took: {}",
+        private_share.id,
+        TimeFormat(start.elapsed())
+    );
+
+    Ok(private_share)
+}
+
+/// Round of keygen reached by a `KeygenState`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeygenRound {
+    Start,
+    FirstMessage,
+    SecondMessage,
+    ChainCodeFirstMessage,
+    Done,
+}
+
+/// Client side of keygen as a sequence of rounds with P1.
+///
+/// The state is serializable, so it can be persisted after each `step` and resumed after an
+/// app restart or a dropped connection; a failed `step` leaves the state unchanged and can be
+/// retried. A session that will not be finished should be closed with `abort`.
+#[derive(Serialize, Deserialize)]
+pub struct KeygenState {
+    round: KeygenRound,
+    id: Option<String>,
+    kg_party_one_first_message: Option<party_one::KeyGenFirstMsg>,
+    kg_party_two_first_message: Option<party_two::KeyGenFirstMsg>,
+    kg_ec_key_pair_party2: Option<party_two::EcKeyPair>,
+    kg_party_one_second_message: Option<party1::KeyGenParty1Message2>,
+    party_two_paillier: Option<party_two::PaillierPublic>,
+    cc_party_one_first_message: Option<Party1FirstMessage>,
+    cc_party_two_first_message: Option<Party2FirstMessage<GE>>,
+    cc_ec_key_pair2: Option<EcKeyPair<GE>>,
+    private_share: Option<PrivateShare>,
+}
+
+impl Default for KeygenState {
+    fn default() -> Self {
+        KeygenState::new()
+    }
+}
+
+fn missing(field: &str) -> TssError {
+    TssError::protocol("keygen", format!("state has no {}", field))
+}
+
+impl KeygenState {
+    pub fn new() -> KeygenState {
+        KeygenState {
+            round: KeygenRound::Start,
+            id: None,
+            kg_party_one_first_message: None,
+            kg_party_two_first_message: None,
+            kg_ec_key_pair_party2: None,
+            kg_party_one_second_message: None,
+            party_two_paillier: None,
+            cc_party_one_first_message: None,
+            cc_party_two_first_message: None,
+            cc_ec_key_pair2: None,
+            private_share: None,
+        }
+    }
+
+    pub fn round(&self) -> KeygenRound {
+        self.round
+    }
+
+    /// Server session id, known once P1 answered the first message.
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.round == KeygenRound::Done
+    }
+
+    pub fn into_private_share(self) -> Result<PrivateShare, TssError> {
+        match self.private_share {
+            Some(private_share) if self.round == KeygenRound::Done => Ok(private_share),
+            _ => Err(TssError::protocol("keygen", "keygen is not finished")),
+        }
+    }
+
+    /// Path and body of the next message to P1, `None` once keygen is done.
+    pub fn next_request(&self) -> Result<Option<(String, serde_json::Value)>, TssError> {
+        let id = || self.id.as_deref().ok_or_else(|| missing("id"));
+
+        let request = match self.round {
+            KeygenRound::Start => (format!("{}/first", KG_PATH_PRE), serde_json::json!({})),
+            KeygenRound::FirstMessage => {
+                let kg_party_two_first_message = self
+                    .kg_party_two_first_message
+                    .as_ref()
+                    .ok_or_else(|| missing("kg_party_two_first_message"))?;
+                (
+                    format!("{}/{}/second", KG_PATH_PRE, id()?),
+                    serde_json::to_value(&kg_party_two_first_message.d_log_proof)?,
+                )
+            }
+            KeygenRound::SecondMessage => (
+                format!("{}/{}/chaincode/first", KG_PATH_PRE, id()?),
+                serde_json::json!({}),
+            ),
+            KeygenRound::ChainCodeFirstMessage => {
+                let cc_party_two_first_message = self
+                    .cc_party_two_first_message
+                    .as_ref()
+                    .ok_or_else(|| missing("cc_party_two_first_message"))?;
+                (
+                    format!("{}/{}/chaincode/second", KG_PATH_PRE, id()?),
+                    serde_json::to_value(&cc_party_two_first_message.d_log_proof)?,
+                )
+            }
+            KeygenRound::Done => return Ok(None),
         };
+        Ok(Some(request))
+    }
+
+    /// Consume P1's reply to `next_request` and move to the next round. On error the state
+    /// is left as it was.
+    pub fn apply_response(&mut self, response: serde_json::Value) -> Result<(), TssError> {
+        match self.round {
+            KeygenRound::Start => {
+                let (id, kg_party_one_first_message): (String, party_one::KeyGenFirstMsg) =
+                    serde_json::from_value(response)?;
+                let (kg_party_two_first_message, kg_ec_key_pair_party2) =
+                    MasterKey2::key_gen_first_message();
+
+                self.id = Some(id);
+                self.kg_party_one_first_message = Some(kg_party_one_first_message);
+                self.kg_party_two_first_message = Some(kg_party_two_first_message);
+                self.kg_ec_key_pair_party2 = Some(kg_ec_key_pair_party2);
+                self.round = KeygenRound::FirstMessage;
+            }
+            KeygenRound::FirstMessage => {
+                let kg_party_one_second_message: party1::KeyGenParty1Message2 =
+                    serde_json::from_value(response)?;
+                let party_two_paillier = compute_party_two_paillier(
+                    self.kg_party_one_first_message
+                        .as_ref()
+                        .ok_or_else(|| missing("kg_party_one_first_message"))?,
+                    &kg_party_one_second_message,
+                )?;
+
+                self.kg_party_one_second_message = Some(kg_party_one_second_message);
+                self.party_two_paillier = Some(party_two_paillier);
+                self.round = KeygenRound::SecondMessage;
+            }
+            KeygenRound::SecondMessage => {
+                let cc_party_one_first_message: Party1FirstMessage =
+                    serde_json::from_value(response)?;
+                let (cc_party_two_first_message, cc_ec_key_pair2) =
+                    chain_code::party2::ChainCode2::chain_code_first_message();
 
-    let (kg_party_two_first_message, kg_ec_key_pair_party2) = MasterKey2::key_gen_first_message();
+                self.cc_party_one_first_message = Some(cc_party_one_first_message);
+                self.cc_party_two_first_message = Some(cc_party_two_first_message);
+                self.cc_ec_key_pair2 = Some(cc_ec_key_pair2);
+                self.round = KeygenRound::ChainCodeFirstMessage;
+            }
+            KeygenRound::ChainCodeFirstMessage => {
+                let cc_party_one_second_message: Party1SecondMessage<GE> =
+                    serde_json::from_value(response)?;
+                let master_key = compute_master_key(
+                    self.kg_ec_key_pair_party2
+                        .as_ref()
+                        .ok_or_else(|| missing("kg_ec_key_pair_party2"))?,
+                    self.kg_party_one_second_message
+                        .as_ref()
+                        .ok_or_else(|| missing("kg_party_one_second_message"))?,
+                    self.party_two_paillier
+                        .as_ref()
+                        .ok_or_else(|| missing("party_two_paillier"))?,
+                    self.cc_ec_key_pair2
+                        .as_ref()
+                        .ok_or_else(|| missing("cc_ec_key_pair2"))?,
+                    self.cc_party_one_first_message
+                        .as_ref()
+                        .ok_or_else(|| missing("cc_party_one_first_message"))?,
+                    &cc_party_one_second_message,
+                )?;
+                let id = self.id.clone().ok_or_else(|| missing("id"))?;
 
-    let body = &kg_party_two_first_message.d_log_proof;
+                // Only the finished share is kept, the round secrets are no longer needed
+                *self = KeygenState {
+                    round: KeygenRound::Done,
+                    id: Some(id.clone()),
+                    private_share: Some(PrivateShare { id, master_key }),
+                    ..KeygenState::new()
+                };
+            }
+            KeygenRound::Done => {
+                return Err(TssError::protocol("keygen", "keygen is already finished"))
+            }
+        }
+        Ok(())
+    }
 
-    // Send ECDH key exchange message to P1 & receive the Paillier pubkey from P1
-    let kg_party_one_second_message: party1::KeyGenParty1Message2 =
-        match requests::postb(client_shim, &format!("{}/{}/second", KG_PATH_PRE, id), body)? {
+    /// Run one round with P1.
+    pub fn step<C: Party1Transport>(&mut self, client_shim: &C) -> Result<(), TssError> {
+        let (path, body) = match self.next_request()? {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        let response: serde_json::Value = match client_shim.postb(&path, body)? {
             Some(s) => s,
-            None => return Err(anyhow!("keygen second message request failed")),
+            None => return Err(TssError::protocol(&path, "empty response")),
         };
 
-    let (_, party_two_paillier) = match MasterKey2::key_gen_second_message(
-        &kg_party_one_first_message,
-        &kg_party_one_second_message,
-        SALT_STRING,
-    ) {
-        Ok(s) => s,
-        Err(_) => return Err(anyhow!("calculate paillier public failed")),
-    };
+        self.apply_response(response)
+    }
 
-    // Receive non-interactive zk proof from P1
-    let cc_party_one_first_message: Party1FirstMessage = match requests::post(
-        client_shim,
-        &format!("{}/{}/chaincode/first", KG_PATH_PRE, id),
-    )? {
-        Some(s) => s,
-        None => return Err(anyhow!("chaincode first message request failed")),
-    };
+    /// Run the remaining rounds.
+    pub fn run<C: Party1Transport>(&mut self, client_shim: &C) -> Result<(), TssError> {
+        while !self.is_done() {
+            self.step(client_shim)?;
+        }
+        Ok(())
+    }
+
+    /// Tell P1 to drop an unfinished session.
+    pub fn abort<C: Party1Transport>(self, client_shim: &C) -> Result<(), TssError> {
+        if self.is_done() {
+            return Ok(());
+        }
+        if let Some(id) = self.id() {
+            client_shim.post::<serde_json::Value>(&format!("{}/{}/abort", KG_PATH_PRE, id))?;
+        }
+        Ok(())
+    }
+}
+
+// Local steps of keygen, applied to P1's replies by `KeygenState`.
 
-    let (cc_party_two_first_message, cc_ec_key_pair2) =
-        chain_code::party2::ChainCode2::chain_code_first_message();
+fn compute_party_two_paillier(
+    kg_party_one_first_message: &party_one::KeyGenFirstMsg,
+    kg_party_one_second_message: &party1::KeyGenParty1Message2,
+) -> Result<party_two::PaillierPublic, TssError> {
+    // `key_gen_second_message` folds every check into one `Err(())`; run the ones we can
+    // name first so a failure says which proof of P1 is wrong.
+    if party_two::KeyGenSecondMsg::verify_commitments_and_dlog_proof(
+        kg_party_one_first_message,
+        &kg_party_one_second_message.ecdh_second_message,
+    )
+    .is_err()
+    {
+        return Err(TssError::verification(
+            "keygen second message",
+            "commitment or dlog proof of P1 public share failed",
+        ));
+    }
 
-    let body = &cc_party_two_first_message.d_log_proof;
+    if kg_party_one_second_message
+        .correct_key_proof
+        .verify(&kg_party_one_second_message.ek, SALT_STRING)
+        .is_err()
+    {
+        return Err(TssError::verification(
+            "keygen second message",
+            "Paillier correct key proof failed",
+        ));
+    }
 
-    // Initiate 2-round zk proof with P1 & receive the decom proof from P1
-    let cc_party_one_second_message: Party1SecondMessage<GE> = match requests::postb(
-        client_shim,
-        &format!("{}/{}/chaincode/second", KG_PATH_PRE, id),
-        body,
-    )? {
-        Some(s) => s,
-        None => return Err(anyhow!("chaincode second message request failed")),
+    let (_party_two_second_message, party_two_paillier) = match MasterKey2::key_gen_second_message(
+        kg_party_one_first_message,
+        kg_party_one_second_message,
+        SALT_STRING,
+    ) {
+        Ok(s) => s,
+        Err(_) => {
+            return Err(TssError::verification(
+                "keygen second message",
+                "PDL proof of P1 encrypted share failed",
+            ))
+        }
     };
 
+    Ok(party_two_paillier)
+}
+
+fn compute_master_key(
+    kg_ec_key_pair_party2: &party_two::EcKeyPair,
+    kg_party_one_second_message: &party1::KeyGenParty1Message2,
+    party_two_paillier: &party_two::PaillierPublic,
+    cc_ec_key_pair2: &EcKeyPair<GE>,
+    cc_party_one_first_message: &Party1FirstMessage,
+    cc_party_one_second_message: &Party1SecondMessage<GE>,
+) -> Result<MasterKey2, TssError> {
     let cc_party_two_second_message = chain_code::party2::ChainCode2::chain_code_second_message(
-        &cc_party_one_first_message,
-        &cc_party_one_second_message,
+        cc_party_one_first_message,
+        cc_party_one_second_message,
     );
 
-    assert!(cc_party_two_second_message.is_ok());
+    if cc_party_two_second_message.is_err() {
+        return Err(TssError::verification(
+            "keygen chaincode second message",
+            "commitment or dlog proof of P1 chain code share failed",
+        ));
+    }
 
     let party2_cc = chain_code::party2::ChainCode2::compute_chain_code(
-        &cc_ec_key_pair2,
+        cc_ec_key_pair2,
         &cc_party_one_second_message.comm_witness.public_share,
     )
     .chain_code;
@@ -92,17 +342,39 @@ pub fn get_private_share(client_shim: &ClientShim) -> Result<PrivateShare> {
     // Verify zk proof, generate c_key & paillier pubkey of P1
     let master_key = MasterKey2::set_master_key(
         &party2_cc,
-        &kg_ec_key_pair_party2,
+        kg_ec_key_pair_party2,
         &kg_party_one_second_message
             .ecdh_second_message
             .comm_witness
             .public_share,
-        &party_two_paillier,
+        party_two_paillier,
     );
 
-    println!("(id: {}) Took: {}", id, TimeFormat(start.elapsed()));
+    check_master_key(&master_key, kg_ec_key_pair_party2)?;
+    Ok(master_key)
+}
+
+// Self check of the finished share: Q must be P1 * x2 and P2 our own public share.
+fn check_master_key(
+    master_key: &MasterKey2,
+    kg_ec_key_pair_party2: &party_two::EcKeyPair,
+) -> Result<(), TssError> {
+    if master_key.public.p2 != kg_ec_key_pair_party2.public_share {
+        return Err(TssError::verification(
+            "keygen master key",
+            "P2 public share does not match the local key pair",
+        ));
+    }
+
+    let q = party_two::compute_pubkey(kg_ec_key_pair_party2, &master_key.public.p1);
+    if master_key.public.q != q {
+        return Err(TssError::verification(
+            "keygen master key",
+            "joint public key is not P1 * x2",
+        ));
+    }
 
-    Ok(PrivateShare { id, master_key })
+    Ok(())
 }
 
 #[no_mangle]
@@ -112,36 +384,22 @@ pub extern "C" fn get_client_master_key(
     c_auth_token: *const c_char,
     c_user_id: *const c_char,
 ) -> *mut c_char {
-    let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let private_share: PrivateShare = match get_private_share(&client_shim) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "private_share".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+    ffi_guard("get_client_master_key", || {
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let private_share_json = match serde_json::to_string(&private_share) {
-        Ok(share) => share,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "private_share".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let private_share: PrivateShare = match get_private_share(&client_shim) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "private_share".to_owned(),
+                    e,
+                })
+            }
+        };
 
-    match CString::new(private_share_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "private_share".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        ok_to_c_string(&private_share, "private_share")
+    })
 }
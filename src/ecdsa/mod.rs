@@ -1,8 +1,17 @@
+pub mod a_keygen;
+pub mod a_rotate;
+pub mod a_sign;
+pub mod exit;
 pub mod keygen;
+pub mod presign;
 pub mod recover;
 pub mod rotate;
 pub mod sign;
 
+pub use a_keygen::a_get_private_share;
+pub use a_rotate::a_rotate_private_share;
+pub use a_sign::{a_sign, a_sign_batch};
 pub use keygen::get_private_share;
+pub use presign::{sign_with_pool, sign_with_presignature, PresignaturePool};
 pub use rotate::rotate_private_share;
-pub use sign::sign;
+pub use sign::{sign, sign_batch, SignItem};
@@ -0,0 +1,216 @@
+// Presignatures: the ephemeral key exchange of signing (round 1) does not depend on the
+// message, so it can run ahead of time. A later signature then only needs round 2.
+
+use std::collections::VecDeque;
+
+use curv::elliptic::curves::secp256_k1::GE;
+use curv::BigInt;
+use kms::ecdsa::two_party::MasterKey2;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_two;
+
+use super::sign::{sign, SignState, SIGN_PATH_PRE};
+use crate::dto::ecdsa::PrivateShare;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::transport::Party1Transport;
+
+/// Finished round 1 of signing, agreed with P1 under `presign_id`. Usable exactly once.
+#[derive(Serialize, Deserialize)]
+pub struct Presignature {
+    pub presign_id: String,
+    eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    eph_comm_witness: party_two::EphCommWitness,
+    eph_ec_key_pair_party2: party_two::EphEcKeyPair,
+    sign_party_one_first_message: party_one::EphKeyGenFirstMsg,
+}
+
+/// Presignatures of one master key. They hold ephemeral secrets, so the pool must be
+/// stored like the private share it belongs to.
+///
+/// The pool remembers P2's public share of the master key it was filled for: after a
+/// rotation it is emptied instead of handing out presignatures P1 no longer accepts.
+#[derive(Serialize, Deserialize, Default)]
+pub struct PresignaturePool {
+    key_share: Option<GE>,
+    presignatures: VecDeque<Presignature>,
+}
+
+impl PresignaturePool {
+    pub fn new() -> PresignaturePool {
+        PresignaturePool::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.presignatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.presignatures.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.key_share = None;
+        self.presignatures.clear();
+    }
+
+    /// Run round 1 with P1 `count` times in a single request and keep the results.
+    /// Returns the pool size.
+    pub fn refill<C: Party1Transport>(
+        &mut self,
+        client_shim: &C,
+        private_share: &PrivateShare,
+        count: usize,
+    ) -> Result<usize, TssError> {
+        self.check_key(private_share);
+        if count == 0 {
+            return Ok(self.len());
+        }
+
+        let ephemeral: Vec<_> = (0..count)
+            .map(|_| MasterKey2::sign_first_message())
+            .collect();
+        let first_messages: Vec<&party_two::EphKeyGenFirstMsg> =
+            ephemeral.iter().map(|(first, _, _)| first).collect();
+
+        let replies: Vec<(String, party_one::EphKeyGenFirstMsg)> = match client_shim.postb(
+            &format!("{}/{}/presign", SIGN_PATH_PRE, private_share.id),
+            &first_messages,
+        )? {
+            Some(s) => s,
+            None => return Err(TssError::protocol("presign", "empty response")),
+        };
+        if replies.len() != count {
+            return Err(TssError::protocol(
+                "presign",
+                format!("expected {} messages, got {}", count, replies.len()),
+            ));
+        }
+
+        for (
+            (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2),
+            (presign_id, p1_first),
+        ) in ephemeral.into_iter().zip(replies)
+        {
+            self.presignatures.push_back(Presignature {
+                presign_id,
+                eph_key_gen_first_message_party_two,
+                eph_comm_witness,
+                eph_ec_key_pair_party2,
+                sign_party_one_first_message: p1_first,
+            });
+        }
+        Ok(self.len())
+    }
+
+    /// Remove the oldest presignature for `private_share`. Persist the pool before using
+    /// it, so a crash cannot lead to handing the same presignature out twice
+    /// (`PresignatureStore::persist`).
+    pub fn take(&mut self, private_share: &PrivateShare) -> Option<Presignature> {
+        self.check_key(private_share);
+        self.presignatures.pop_front()
+    }
+
+    // Drop everything computed for another (pre-rotation) key share
+    fn check_key(&mut self, private_share: &PrivateShare) {
+        let key_share = private_share.master_key.public.p2;
+        if self.key_share != Some(key_share) {
+            self.clear();
+            self.key_share = Some(key_share);
+        }
+    }
+}
+
+/// Where a pool is kept between signatures. `sign_with_pool` calls `persist` after taking a
+/// presignature and before its second round.
+pub trait PresignatureStore {
+    fn pool(&mut self) -> &mut PresignaturePool;
+
+    fn persist(&mut self) -> Result<(), TssError>;
+}
+
+// A pool held in memory only (e.g. by an FFI session) is gone with the process, so a taken
+// presignature cannot come back
+impl PresignatureStore for PresignaturePool {
+    fn pool(&mut self) -> &mut PresignaturePool {
+        self
+    }
+
+    fn persist(&mut self) -> Result<(), TssError> {
+        Ok(())
+    }
+}
+
+/// Sign `message` with the child key `mk` at (`x_pos`, `y_pos`) using a presignature: one
+/// round trip instead of two.
+pub fn sign_with_presignature<C: Party1Transport>(
+    client_shim: &C,
+    presignature: Presignature,
+    message: BigInt,
+    mk: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+    id: &str,
+) -> Result<party_one::SignatureRecid, TssError> {
+    let mut state = SignState::from_ephemeral(
+        message,
+        x_pos,
+        y_pos,
+        presignature.eph_key_gen_first_message_party_two,
+        presignature.eph_comm_witness,
+        presignature.eph_ec_key_pair_party2,
+    );
+    let request =
+        state.handle_party_one_first_message(mk, &presignature.sign_party_one_first_message)?;
+
+    let signature: party_one::SignatureRecid = match client_shim.postb(
+        &format!(
+            "{}/{}/presign/{}/second",
+            SIGN_PATH_PRE, id, presignature.presign_id
+        ),
+        request,
+    )? {
+        Some(s) => s,
+        None => {
+            return Err(TssError::protocol(
+                "presign second message",
+                "empty response",
+            ))
+        }
+    };
+
+    state.handle_signature(mk, signature)?;
+    state.into_signature()
+}
+
+/// Sign with a presignature from `store` when there is one, with the full protocol
+/// otherwise. The shortened pool is persisted before the presignature is used. A
+/// presignature P1 refuses (e.g. dropped on its side) empties the pool and the message is
+/// signed with the full protocol.
+pub fn sign_with_pool<C: Party1Transport, S: PresignatureStore>(
+    client_shim: &C,
+    store: &mut S,
+    private_share: &PrivateShare,
+    message: BigInt,
+    mk: &MasterKey2,
+    x_pos: BigInt,
+    y_pos: BigInt,
+) -> Result<party_one::SignatureRecid, TssError> {
+    let id = &private_share.id;
+    if let Some(presignature) = store.pool().take(private_share) {
+        store.persist()?;
+        match sign_with_presignature(
+            client_shim,
+            presignature,
+            message.clone(),
+            mk,
+            x_pos.clone(),
+            y_pos.clone(),
+            id,
+        ) {
+            Err(TssError::Protocol { .. }) => store.pool().clear(),
+            result => return result,
+        }
+    }
+
+    sign(client_shim, message, mk, x_pos, y_pos, id)
+}
@@ -1,28 +1,33 @@
-use anyhow::Result;
 use centipede::juggling::proof_system::{Helgamalsegmented, Proof};
 use centipede::juggling::segmentation::Msegmentation;
-use centipede::Errors;
 use curv::arithmetic::{Converter, Modulo};
 use curv::elliptic::curves::secp256_k1::{FE, GE};
 use curv::elliptic::curves::traits::{ECPoint, ECScalar};
 use curv::BigInt;
-use kms::chain_code::two_party::party2::ChainCode2;
-use kms::ecdsa::two_party::{MasterKey1, MasterKey2, Party2Public};
+use kms::ecdsa::two_party::{MasterKey1, MasterKey2};
 use serde_json;
 // iOS bindings
-use std::ffi::CString;
 use std::os::raw::c_char;
+use zeroize::{Zeroize, Zeroizing};
 
+use crate::dto::backup::{now_unix_secs, ClientBackup, CLIENT_BACKUP_VERSION};
 use crate::dto::ecdsa::PrivateShare;
-use crate::escrow::{self, Escrow};
-use crate::utilities::err_handling::{error_to_c_string, ErrorFFIKind};
-use crate::utilities::ffi::ffi_utils::{get_private_share_from_raw, get_str_from_c_char};
-
-pub fn backup_client_mk(private_share: &PrivateShare) -> Result<String, ErrorFFIKind> {
-    let escrow = Escrow::new();
-
+use crate::escrow;
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind, TssError};
+use crate::utilities::ffi::ffi_utils::{
+    ffi_guard, get_private_share_from_raw, get_str_from_c_char,
+};
+
+/// Encrypt the client share to the escrow public key `y`. `coin_type` and `network` are
+/// recorded in the backup for recovery.
+pub fn backup_client_mk(
+    private_share: &PrivateShare,
+    y: &GE,
+    coin_type: Option<&str>,
+    network: Option<&str>,
+) -> Result<String, TssError> {
+    let y = *y;
     let g: GE = ECPoint::generator();
-    let y = escrow.get_public_key();
     let (segments, encryptions) = private_share.master_key.private.to_encrypted_segment(
         escrow::SEGMENT_SIZE,
         escrow::NUM_SEGMENTS,
@@ -32,59 +37,91 @@ pub fn backup_client_mk(private_share: &PrivateShare) -> Result<String, ErrorFFI
 
     let proof = Proof::prove(&segments, &encryptions, &g, &y, &escrow::SEGMENT_SIZE);
 
-    match serde_json::to_string(&(
+    let client_backup = ClientBackup {
+        version: CLIENT_BACKUP_VERSION,
+        id: private_share.id.clone(),
+        coin_type: coin_type.map(str::to_owned),
+        network: network.map(str::to_owned),
+        segment_size: escrow::SEGMENT_SIZE,
+        num_segments: escrow::NUM_SEGMENTS,
+        escrow_key_fingerprint: Some(escrow::fingerprint(&y)),
+        created_at: now_unix_secs(),
         encryptions,
         proof,
-        private_share.master_key.public.clone(),
-        private_share.master_key.chain_code.clone(),
-        &private_share.id,
-    )) {
-        Ok(s) => Ok(s),
-        Err(e) => Err(ErrorFFIKind::E102 {
-            msg: "client_backup".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        public: private_share.master_key.public.clone(),
+        chain_code: private_share.master_key.chain_code.clone(),
+    };
+
+    client_backup.to_json()
 }
 
-pub fn verify_client_backup(y: GE, backup_data: &str) -> Result<(), Errors> {
+/// Check that the backup (current or legacy format) holds P2's share encrypted to `y`.
+pub fn verify_client_backup(y: GE, backup_data: &str) -> Result<(), TssError> {
     let g: GE = ECPoint::generator();
 
-    let (encryptions, proof, client_public, _, _): (
-        Helgamalsegmented,
-        Proof,
-        Party2Public,
-        ChainCode2,
-        String,
-    ) = serde_json::from_str(backup_data).unwrap();
-    proof.verify(
-        &encryptions,
+    let client_backup = ClientBackup::from_json(backup_data)?;
+    if let Some(fingerprint) = &client_backup.escrow_key_fingerprint {
+        if *fingerprint != escrow::fingerprint(&y) {
+            return Err(TssError::verification(
+                "verify client backup",
+                format!("backup is encrypted to escrow key {}", fingerprint),
+            ));
+        }
+    }
+
+    match client_backup.proof.verify(
+        &client_backup.encryptions,
         &g,
         &y,
-        &client_public.p2,
-        &escrow::SEGMENT_SIZE,
-    )
+        &client_backup.public.p2,
+        &client_backup.segment_size,
+    ) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(TssError::verification(
+            "verify client backup",
+            format!("{:?}", e),
+        )),
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn backup(c_private_share_json: *const c_char) -> *mut c_char {
-    let private_share: PrivateShare = match get_private_share_from_raw(c_private_share_json) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+pub extern "C" fn backup(
+    c_private_share_json: *const c_char,
+    c_escrow_pubkey: *const c_char,
+) -> *mut c_char {
+    ffi_guard("backup", || {
+        let private_share: PrivateShare = match get_private_share_from_raw(c_private_share_json) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let client_backup_json = match backup_client_mk(&private_share) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let escrow_pubkey = match get_str_from_c_char(c_escrow_pubkey, "escrow_pubkey") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    match CString::new(client_backup_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "client_backup".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        let y = match escrow::parse_public_key(&escrow_pubkey) {
+            Ok(y) => y,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "escrow_pubkey".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
+
+        let client_backup_json = match backup_client_mk(&private_share, &y, None, None) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "client_backup".to_owned(),
+                    e,
+                })
+            }
+        };
+
+        ok_to_c_string(&client_backup_json, "client_backup")
+    })
 }
 
 #[no_mangle]
@@ -92,42 +129,35 @@ pub extern "C" fn verify_backup(
     c_escrow_pubkey: *const c_char,
     c_backup_data: *const c_char,
 ) -> *mut c_char {
-    let backup_data = match get_str_from_c_char(c_backup_data, "backup_data") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+    ffi_guard("verify_backup", || {
+        let backup_data = match get_str_from_c_char(c_backup_data, "backup_data") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let escrow_pubkey = match get_str_from_c_char(c_escrow_pubkey, "escrow_pubkey") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let escrow_pubkey = match get_str_from_c_char(c_escrow_pubkey, "escrow_pubkey") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let y = match serde_json::from_str::<GE>(&escrow_pubkey) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "escrow_pubkey".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let y = match escrow::parse_public_key(&escrow_pubkey) {
+            Ok(y) => y,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "escrow_pubkey".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    match verify_client_backup(y, &backup_data) {
-        Ok(_x) => match CString::new("success") {
-            Ok(s) => s.into_raw(),
-            Err(e) => error_to_c_string(ErrorFFIKind::E101 {
+        match verify_client_backup(y, &backup_data) {
+            Ok(_x) => ok_to_c_string(&true, "verify_client_backup"),
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
                 msg: "verify_client_backup".to_owned(),
-                e: e.to_string(),
+                e,
             }),
-        },
-        Err(_e) => match CString::new("failed") {
-            Ok(s) => s.into_raw(),
-            Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-                msg: "verify_client_backup".to_owned(),
-                e: e.to_string(),
-            }),
-        },
-    }
+        }
+    })
 }
 
 #[no_mangle]
@@ -137,84 +167,74 @@ pub extern "C" fn decrypt_party_one_master_key(
     c_helgamal_segmented_json: *const c_char,
     c_private_key: *const c_char,
 ) -> *mut c_char {
-    let segment_size = 8; // This is hardcoded on both client and server side
+    ffi_guard("decrypt_party_one_master_key", || {
+        let segment_size = 8; // This is hardcoded on both client and server side
 
-    let g: GE = GE::generator();
-    let master_key_two_json = match get_str_from_c_char(c_p2_master_key_json, "master_key_two_json")
-    {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let g: GE = GE::generator();
+        let master_key_two_json =
+            match get_str_from_c_char(c_p2_master_key_json, "master_key_two_json") {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
 
-    let p2_master_key: MasterKey2 = match serde_json::from_str(&master_key_two_json) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "p2_master_key".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
-    let helgamal_segmented_json =
-        match get_str_from_c_char(c_helgamal_segmented_json, "helgamal_segmented_json") {
+        let p2_master_key: MasterKey2 = match serde_json::from_str(&master_key_two_json) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "p2_master_key".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
+        let helgamal_segmented_json =
+            match get_str_from_c_char(c_helgamal_segmented_json, "helgamal_segmented_json") {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        let p1_encryptions: Helgamalsegmented = match serde_json::from_str(&helgamal_segmented_json)
+        {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "p1_encryptions".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
+        let private_key = match get_str_from_c_char(c_private_key, "private_key") {
             Ok(s) => s,
             Err(e) => return error_to_c_string(e),
         };
 
-    let p1_encryptions: Helgamalsegmented = match serde_json::from_str(&helgamal_segmented_json) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "p1_encryptions".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
-    let private_key = match get_str_from_c_char(c_private_key, "private_key") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let y_b: BigInt = match serde_json::from_str(&private_key) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "p1_encryptions".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let y_b: BigInt = match serde_json::from_str(&private_key) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "p1_encryptions".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let y: FE = ECScalar::from(&y_b);
+        let y: FE = ECScalar::from(&y_b);
 
-    let r = match Msegmentation::decrypt(&p1_encryptions, &g, &y, &segment_size) {
-        Ok(s) => s,
-        Err(_e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "secp256k1_scalar".to_owned(),
-                e: "Secp256k1 Error Decrypting".to_owned(),
-            })
-        }
-    };
-    let p1_master_key_recovered = p2_master_key.counter_master_key_from_recovered_secret(r);
-
-    let s = match serde_json::to_string(&p1_master_key_recovered) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "p1_master_key_recovered".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let r = match Msegmentation::decrypt(&p1_encryptions, &g, &y, &segment_size) {
+            Ok(s) => s,
+            Err(_e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "secp256k1_scalar".to_owned(),
+                    e: TssError::verification(
+                        "decrypt party one master key",
+                        "Secp256k1 Error Decrypting",
+                    ),
+                })
+            }
+        };
+        let p1_master_key_recovered = p2_master_key.counter_master_key_from_recovered_secret(r);
 
-    match CString::new(s) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "client_backup".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        ok_to_c_string(&p1_master_key_recovered, "p1_master_key_recovered")
+    })
 }
 
 #[no_mangle]
@@ -223,38 +243,30 @@ pub extern "C" fn get_child_mk1(
     c_x_pos: i32,
     c_y_pos: i32,
 ) -> *mut c_char {
-    let master_key_one_json = match get_str_from_c_char(c_p1_master_key_json, "master_key_one_json")
-    {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let party_one_master_key: MasterKey1 = match serde_json::from_str(&master_key_one_json) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "party_one_master_key".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
-
-    let x: BigInt = BigInt::from(c_x_pos);
-    let y: BigInt = BigInt::from(c_y_pos);
+    ffi_guard("get_child_mk1", || {
+        let master_key_one_json =
+            match get_str_from_c_char(c_p1_master_key_json, "master_key_one_json") {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        let party_one_master_key: MasterKey1 = match serde_json::from_str(&master_key_one_json) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "party_one_master_key".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let derived_mk1 = party_one_master_key.get_child(vec![x, y]);
+        let x: BigInt = BigInt::from(c_x_pos);
+        let y: BigInt = BigInt::from(c_y_pos);
 
-    let derived_mk1_json = match serde_json::to_string(&derived_mk1) {
-        Ok(share) => share,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "derived_mk1_json".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let derived_mk1 = party_one_master_key.get_child(vec![x, y]);
 
-    CString::new(derived_mk1_json).unwrap().into_raw()
+        ok_to_c_string(&derived_mk1, "derived_mk1_json")
+    })
 }
 
 #[no_mangle]
@@ -263,44 +275,30 @@ pub extern "C" fn get_child_mk2(
     c_x_pos: i32,
     c_y_pos: i32,
 ) -> *mut c_char {
-    let master_key_two_json = match get_str_from_c_char(c_p2_master_key_json, "master_key_two_json")
-    {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let party_two_master_key: MasterKey2 = match serde_json::from_str(&master_key_two_json) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "party_two_master_key".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
-
-    let x: BigInt = BigInt::from(c_x_pos);
-    let y: BigInt = BigInt::from(c_y_pos);
+    ffi_guard("get_child_mk2", || {
+        let master_key_two_json =
+            match get_str_from_c_char(c_p2_master_key_json, "master_key_two_json") {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        let party_two_master_key: MasterKey2 = match serde_json::from_str(&master_key_two_json) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "party_two_master_key".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let derived_mk2 = party_two_master_key.get_child(vec![x, y]);
+        let x: BigInt = BigInt::from(c_x_pos);
+        let y: BigInt = BigInt::from(c_y_pos);
 
-    let derived_mk2_json = match serde_json::to_string(&derived_mk2) {
-        Ok(share) => share,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "derived_mk2_json".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let derived_mk2 = party_two_master_key.get_child(vec![x, y]);
 
-    match CString::new(derived_mk2_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "derived_mk2_json".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        ok_to_c_string(&derived_mk2, "derived_mk2_json")
+    })
 }
 
 #[no_mangle]
@@ -308,30 +306,41 @@ pub extern "C" fn construct_single_private_key(
     c_mk1_x1: *const c_char,
     c_mk2_x2: *const c_char,
 ) -> *mut c_char {
-    let mk1_x1_str = match get_str_from_c_char(c_mk1_x1, "mk1_x1") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-    let mk1_x1: BigInt = BigInt::from_hex(&mk1_x1_str).unwrap();
-
-    let mk2_x2_str = match get_str_from_c_char(c_mk2_x2, "mk2_x2") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-    let mk2_x2: BigInt = BigInt::from_hex(&mk2_x2_str).unwrap();
+    ffi_guard("construct_single_private_key", || {
+        let mk1_x1_str = match get_str_from_c_char(c_mk1_x1, "mk1_x1") {
+            Ok(s) => Zeroizing::new(s),
+            Err(e) => return error_to_c_string(e),
+        };
+        let mut mk1_x1: BigInt = match BigInt::from_hex(&mk1_x1_str) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "mk1_x1".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let s_pk = BigInt::mod_mul(&mk1_x1, &mk2_x2, &FE::q());
+        let mk2_x2_str = match get_str_from_c_char(c_mk2_x2, "mk2_x2") {
+            Ok(s) => Zeroizing::new(s),
+            Err(e) => return error_to_c_string(e),
+        };
+        let mut mk2_x2: BigInt = match BigInt::from_hex(&mk2_x2_str) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "mk2_x2".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let s_pk_json = match serde_json::to_string(&s_pk) {
-        Ok(share) => share,
-        Err(_) => panic!("Error while construct_single_private_key"),
-    };
+        let mut s_pk = BigInt::mod_mul(&mk1_x1, &mk2_x2, &FE::q());
 
-    match CString::new(s_pk_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "derived_mk2_json".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+        let s_pk_json = ok_to_c_string(&s_pk, "s_pk_json");
+        for secret in [&mut mk1_x1, &mut mk2_x2, &mut s_pk] {
+            secret.zeroize();
+        }
+        s_pk_json
+    })
 }
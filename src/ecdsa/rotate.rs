@@ -1,40 +1,98 @@
+use crate::btc::utils::{to_bitcoin_address, BTC_TESTNET};
 use crate::dto::ecdsa::PrivateShare;
-use crate::utilities::err_handling::{error_to_c_string, ErrorFFIKind};
-use crate::utilities::ffi::ffi_utils::{get_client_shim_from_raw, get_private_share_from_raw};
-use crate::utilities::requests::ClientShim;
+use crate::eth::utils::pubkey_to_eth_address;
+use crate::utilities::derive_new_key;
+use crate::utilities::err_handling::{
+    error_to_c_string, ok_and_error_to_c_string, ok_to_c_string, ErrorFFIKind, RequestError,
+    TssError,
+};
+use crate::utilities::ffi::ffi_utils::{
+    ffi_guard, get_client_shim_from_raw, get_private_share_from_raw,
+};
+use crate::utilities::transport::Party1Transport;
 
-use super::super::utilities::requests;
+use curv::cryptographic_primitives::proofs::sigma_valid_pedersen_blind::{
+    PedersenBlindingProof, ProvePederesenBlind,
+};
 use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
 use curv::elliptic::curves::secp256_k1::GE;
 
-use anyhow::{anyhow, Result};
 use kms::ecdsa::two_party::*;
 use kms::rotation::two_party::party2::Rotation2;
 use zk_paillier::zkproofs::SALT_STRING;
 
-use std::ffi::CString;
 use std::os::raw::c_char;
+use thiserror::Error;
 
-const ROT_PATH_PRE: &str = "ecdsa/rotate";
+pub(crate) const ROT_PATH_PRE: &str = "ecdsa/rotate";
+// P1's answer to a confirm of a rotation it has no record of
+const ROTATION_UNKNOWN_STATUS: u16 = 409;
+// Child keys compared before accepting a rotation
+const ROTATION_CHECKED_ADDRESSES: u32 = 3;
 
-pub fn rotate_private_share(
+/// A rotation that failed. `pending` holds the rotated share when it is open whether P1
+/// switched to it: it must be kept and confirmed again, not dropped.
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct RotationError {
+    pub error: TssError,
+    pub pending: Option<PrivateShare>,
+}
+
+impl From<TssError> for RotationError {
+    fn from(error: TssError) -> Self {
+        RotationError {
+            error,
+            pending: None,
+        }
+    }
+}
+
+/// Rotate the shares in one go: run the protocol and confirm it to P1 at once.
+///
+/// Callers that persist the new share should use `begin_rotation` and `confirm_rotation`
+/// instead, saving the share in between, so a crash cannot lose the share P1 switched to.
+pub fn rotate_private_share<C: Party1Transport>(
     private_share: PrivateShare,
-    client_shim: &ClientShim,
-) -> Result<PrivateShare> {
-    let id = &private_share.id.clone();
+    client_shim: &C,
+) -> Result<PrivateShare, RotationError> {
+    let rotated_private_share = begin_rotation(&private_share, client_shim)?;
+    let confirmed = confirm_rotation(client_shim, &rotated_private_share);
+    rotation_result(confirmed, rotated_private_share)
+}
+
+// Outcome of the confirm of a one-shot rotation, shared by the blocking and the async flow
+pub(crate) fn rotation_result(
+    confirmed: Result<bool, TssError>,
+    rotated_private_share: PrivateShare,
+) -> Result<PrivateShare, RotationError> {
+    match confirmed {
+        Ok(true) => Ok(rotated_private_share),
+        Ok(false) => {
+            Err(TssError::protocol("rotation confirm", "P1 does not know the rotation").into())
+        }
+        Err(error) => Err(RotationError {
+            error,
+            pending: Some(rotated_private_share),
+        }),
+    }
+}
+
+/// Run the rotation protocol. P1 keeps signing with the current shares and holds the new
+/// ones as pending until `confirm_rotation`.
+pub fn begin_rotation<C: Party1Transport>(
+    private_share: &PrivateShare,
+    client_shim: &C,
+) -> Result<PrivateShare, TssError> {
+    let id = &private_share.id;
     let coin_flip_party1_first_message: coin_flip_optimal_rounds::Party1FirstMessage<GE> =
-        match requests::post(client_shim, &format!("{}/{}/first", ROT_PATH_PRE, id)) {
-            Ok(s) => match s {
-                Some(s) => s,
-                None => {
-                    return Err(anyhow!("coin flip p1 first msg return None"));
-                }
-            },
-            Err(e) => {
-                return Err(anyhow!(
-                    "coin flip p1 first msg request for rotating failed:\n {}",
-                    e
-                ));
+        match client_shim.post(&format!("{}/{}/first", ROT_PATH_PRE, id))? {
+            Some(s) => s,
+            None => {
+                return Err(TssError::protocol(
+                    "coin flip p1 first msg",
+                    "empty response",
+                ))
             }
         };
 
@@ -46,91 +104,255 @@ pub fn rotate_private_share(
     let (coin_flip_party1_second_message, rotation_party1_first_message): (
         coin_flip_optimal_rounds::Party1SecondMessage<GE>,
         party1::RotationParty1Message1,
-    ) = match requests::postb(
-        client_shim,
-        &format!("{}/{}/second", ROT_PATH_PRE, id.clone()),
-        body,
-    ) {
-        Ok(s) => match s {
-            Some(s) => s,
-            None => {
-                return Err(anyhow!("coin flip p1 second msg return None"));
-            }
-        },
-        Err(e) => {
-            return Err(anyhow!(
-                "coin flip p1 second msg request for rotating failed:\n {}",
-                e
-            ));
+    ) = match client_shim.postb(&format!("{}/{}/second", ROT_PATH_PRE, id), body)? {
+        Some(s) => s,
+        None => {
+            return Err(TssError::protocol(
+                "coin flip p1 second msg",
+                "empty response",
+            ))
         }
     };
 
-    let random2 = Rotation2::key_rotate_second_message(
-        &coin_flip_party1_second_message,
-        &coin_flip_party2_first_message,
+    rotate_master_key(
+        private_share,
         &coin_flip_party1_first_message,
+        &coin_flip_party2_first_message,
+        &coin_flip_party1_second_message,
+        &rotation_party1_first_message,
+    )
+}
+
+/// Make P1 switch to `rotated_private_share`. Safe to repeat: confirming a rotation P1
+/// already switched to succeeds. Returns `false` when P1 answers that it does not know the
+/// rotation (HTTP 409): it never switched and the current shares stay valid. A server
+/// without the confirm endpoint switched at `rotate/{id}/second` already, which counts as
+/// confirmed. Any other failure leaves it open whether P1 switched.
+pub fn confirm_rotation<C: Party1Transport>(
+    client_shim: &C,
+    rotated_private_share: &PrivateShare,
+) -> Result<bool, TssError> {
+    let confirmed = client_shim.postb_idempotent::<_, serde_json::Value>(
+        &format!("{}/{}/confirm", ROT_PATH_PRE, rotated_private_share.id),
+        &rotated_private_share.master_key.public.p2,
+    );
+    confirm_result(confirmed.map(|_| ()))
+}
+
+// Reading of P1's answer to a confirm, shared by the blocking and the async flow
+pub(crate) fn confirm_result(confirmed: Result<(), RequestError>) -> Result<bool, TssError> {
+    match confirmed {
+        Ok(()) => Ok(true),
+        Err(RequestError::Http {
+            status: ROTATION_UNKNOWN_STATUS,
+            ..
+        }) => Ok(false),
+        Err(e) if e.is_unsupported() => Ok(true),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Local step of rotation, shared by the blocking and the async message flow.
+pub(crate) fn rotate_master_key(
+    private_share: &PrivateShare,
+    coin_flip_party1_first_message: &coin_flip_optimal_rounds::Party1FirstMessage<GE>,
+    coin_flip_party2_first_message: &coin_flip_optimal_rounds::Party2FirstMessage<GE>,
+    coin_flip_party1_second_message: &coin_flip_optimal_rounds::Party1SecondMessage<GE>,
+    rotation_party1_first_message: &party1::RotationParty1Message1,
+) -> Result<PrivateShare, TssError> {
+    // `key_rotate_second_message` panics on a bad opening, check it here first
+    let proof = &coin_flip_party1_second_message.proof;
+    if PedersenBlindingProof::verify(proof).is_err()
+        || proof.com != coin_flip_party1_first_message.commitment
+    {
+        return Err(TssError::verification(
+            "coin flip p1 second msg",
+            "P1 seed does not open its commitment",
+        ));
+    }
+
+    if rotation_party1_first_message
+        .correct_key_proof
+        .verify(&rotation_party1_first_message.ek_new, SALT_STRING)
+        .is_err()
+    {
+        return Err(TssError::verification(
+            "rotation p1 first msg",
+            "Paillier correct key proof failed",
+        ));
+    }
+
+    let random2 = Rotation2::key_rotate_second_message(
+        coin_flip_party1_second_message,
+        coin_flip_party2_first_message,
+        coin_flip_party1_first_message,
     );
 
     let party_two_master_key_rotated = match private_share.master_key.rotate_first_message(
         &random2,
-        &rotation_party1_first_message,
+        rotation_party1_first_message,
         SALT_STRING,
     ) {
         Ok(s) => s,
-        Err(_) => return Err(anyhow!("get rotated master key of p2 failed")),
+        Err(_) => {
+            return Err(TssError::verification(
+                "rotation p1 first msg",
+                "PDL proof of P1 encrypted share failed",
+            ))
+        }
     };
 
-    let private_share = PrivateShare {
-        id: private_share.id,
+    let rotated_private_share = PrivateShare {
+        id: private_share.id.clone(),
         master_key: party_two_master_key_rotated,
     };
-    Ok(private_share)
+    check_rotation_invariants(private_share, &rotated_private_share)?;
+    Ok(rotated_private_share)
 }
 
+// Rotation re-randomizes the shares only: the joint key, the chain code and therefore every
+// address must come out unchanged.
+fn check_rotation_invariants(
+    private_share: &PrivateShare,
+    rotated_private_share: &PrivateShare,
+) -> Result<(), TssError> {
+    let master_key = &private_share.master_key;
+    let rotated_master_key = &rotated_private_share.master_key;
+    if rotated_master_key.public.q != master_key.public.q {
+        return Err(TssError::verification(
+            "rotation invariants",
+            "joint public key changed",
+        ));
+    }
+    if rotated_master_key.chain_code != master_key.chain_code {
+        return Err(TssError::verification(
+            "rotation invariants",
+            "chain code changed",
+        ));
+    }
+
+    for pos in 0..ROTATION_CHECKED_ADDRESSES {
+        let (_, mk) = derive_new_key(private_share, pos);
+        let (child_pos, rotated_mk) = derive_new_key(rotated_private_share, pos);
+        if to_bitcoin_address(BTC_TESTNET, &rotated_mk)? != to_bitcoin_address(BTC_TESTNET, &mk)?
+            || pubkey_to_eth_address(&rotated_mk) != pubkey_to_eth_address(&mk)
+        {
+            return Err(TssError::verification(
+                "rotation invariants",
+                format!("address at position {} changed", child_pos),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the rotation protocol and return the rotated share, which P1 holds as pending. Store
+/// it before calling `confirm_client_rotation`.
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
-pub extern "C" fn get_rotated_client_master_key(
+pub extern "C" fn begin_client_rotation(
     c_endpoint: *const c_char,
     c_auth_token: *const c_char,
     c_user_id: *const c_char,
     c_private_share_json: *const c_char,
 ) -> *mut c_char {
-    let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let private_share: PrivateShare = match get_private_share_from_raw(c_private_share_json) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+    ffi_guard("begin_client_rotation", || {
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let rotated_private_share: PrivateShare =
-        match rotate_private_share(private_share, &client_shim) {
+        let private_share: PrivateShare = match get_private_share_from_raw(c_private_share_json) {
             Ok(s) => s,
-            Err(e) => {
-                return error_to_c_string(ErrorFFIKind::E103 {
-                    msg: "rotated_private_share".to_owned(),
-                    e: e.to_string(),
-                })
-            }
+            Err(e) => return error_to_c_string(e),
         };
 
-    let private_share_json = match serde_json::to_string(&rotated_private_share) {
-        Ok(share) => share,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
+        match begin_rotation(&private_share, &client_shim) {
+            Ok(rotated_private_share) => {
+                ok_to_c_string(&rotated_private_share, "rotated_private_share")
+            }
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
                 msg: "rotated_private_share".to_owned(),
-                e: e.to_string(),
-            })
+                e,
+            }),
         }
-    };
+    })
+}
 
-    match CString::new(private_share_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "rotated_private_share".to_owned(),
-            e: e.to_string(),
-        }),
-    }
+/// Make P1 switch to the share returned by `begin_client_rotation`. `ok` is `true` once P1
+/// uses it and `false` if P1 does not know the rotation (keep the current share). On an
+/// error keep the rotated share and call again later.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn confirm_client_rotation(
+    c_endpoint: *const c_char,
+    c_auth_token: *const c_char,
+    c_user_id: *const c_char,
+    c_rotated_private_share_json: *const c_char,
+) -> *mut c_char {
+    ffi_guard("confirm_client_rotation", || {
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let rotated_private_share: PrivateShare =
+            match get_private_share_from_raw(c_rotated_private_share_json) {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        match confirm_rotation(&client_shim, &rotated_private_share) {
+            Ok(confirmed) => ok_to_c_string(&confirmed, "confirmed"),
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
+                msg: "confirmed".to_owned(),
+                e,
+            }),
+        }
+    })
+}
+
+/// Rotate in one call. If the confirm fails without P1 refusing the rotation, the envelope
+/// carries the error and, in `ok`, the rotated share: keep it and pass it to
+/// `confirm_client_rotation`.
+#[deprecated(note = "use begin_client_rotation and confirm_client_rotation")]
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn get_rotated_client_master_key(
+    c_endpoint: *const c_char,
+    c_auth_token: *const c_char,
+    c_user_id: *const c_char,
+    c_private_share_json: *const c_char,
+) -> *mut c_char {
+    ffi_guard("get_rotated_client_master_key", || {
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let private_share: PrivateShare = match get_private_share_from_raw(c_private_share_json) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        match rotate_private_share(private_share, &client_shim) {
+            Ok(s) => ok_to_c_string(&s, "rotated_private_share"),
+            Err(RotationError {
+                error,
+                pending: Some(pending),
+            }) => ok_and_error_to_c_string(
+                &pending,
+                ErrorFFIKind::Tss {
+                    msg: "rotated_private_share".to_owned(),
+                    e: error,
+                },
+                "rotated_private_share",
+            ),
+            Err(RotationError { error, .. }) => error_to_c_string(ErrorFFIKind::Tss {
+                msg: "rotated_private_share".to_owned(),
+                e: error,
+            }),
+        }
+    })
 }
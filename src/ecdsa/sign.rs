@@ -1,89 +1,398 @@
-use anyhow::{anyhow, Result};
+use curv::arithmetic::traits::BitManipulation;
+use curv::elliptic::curves::secp256_k1::{FE, GE};
+use curv::elliptic::curves::traits::{ECPoint, ECScalar};
 use curv::BigInt;
-use kms::ecdsa::two_party::party2;
 use kms::ecdsa::two_party::MasterKey2;
 use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
 use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_two;
 
-use super::super::utilities::requests;
 use crate::dto::ecdsa::SignSecondMsgRequest;
 use crate::utilities::err_handling::error_to_c_string;
+use crate::utilities::err_handling::ok_to_c_string;
 use crate::utilities::err_handling::ErrorFFIKind;
+use crate::utilities::err_handling::TssError;
+use crate::utilities::ffi::ffi_utils::ffi_guard;
 use crate::utilities::ffi::ffi_utils::get_client_shim_from_raw;
 use crate::utilities::ffi::ffi_utils::get_str_from_c_char;
-use crate::utilities::requests::ClientShim;
+use crate::utilities::transport::Party1Transport;
 
 // iOS bindings
-use std::ffi::CString;
 use std::os::raw::c_char;
 
-pub fn sign(
-    client_shim: &ClientShim,
+pub(crate) const SIGN_PATH_PRE: &str = "/ecdsa/sign";
+
+pub fn sign<C: Party1Transport>(
+    client_shim: &C,
     message: BigInt,
     mk: &MasterKey2,
     x_pos: BigInt,
     y_pos: BigInt,
     id: &str,
-) -> Result<party_one::SignatureRecid> {
+) -> Result<party_one::SignatureRecid, TssError> {
     // Choose ephemeral key
-    let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
-        MasterKey2::sign_first_message();
-
-    let request: party_two::EphKeyGenFirstMsg = eph_key_gen_first_message_party_two;
+    let mut state = SignState::new(message, x_pos, y_pos);
 
     // Repeat Key Generation protocol for ephemeral key to obtain random point on curve that will be used in generating signature
-    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg =
-        match requests::postb(client_shim, &format!("/ecdsa/sign/{}/first", id), &request)? {
-            Some(s) => s,
-            None => return Err(anyhow!("party1 sign first message request failed")),
-        };
+    let sign_party_one_first_message: party_one::EphKeyGenFirstMsg = match client_shim.postb(
+        &format!("{}/{}/first", SIGN_PATH_PRE, id),
+        state.first_message(),
+    )? {
+        Some(s) => s,
+        None => return Err(TssError::protocol("sign first message", "empty response")),
+    };
 
     // Generate encryption of derivative of the signature, called c3
-    let party_two_sign_message = mk.sign_second_message(
-        &eph_ec_key_pair_party2,
-        eph_comm_witness,
-        &sign_party_one_first_message,
-        &message,
-    );
+    let request = state.handle_party_one_first_message(mk, &sign_party_one_first_message)?;
 
     // Send c3 to P1 to verify and get valid signature
-    let signature = match get_signature(
-        client_shim,
-        message,
-        party_two_sign_message,
-        x_pos,
-        y_pos,
-        id,
+    let signature: party_one::SignatureRecid =
+        match client_shim.postb(&format!("{}/{}/second", SIGN_PATH_PRE, id), request)? {
+            Some(s) => s,
+            None => return Err(TssError::protocol("sign second message", "empty response")),
+        };
+
+    state.handle_signature(mk, signature)?;
+    state.into_signature()
+}
+
+/// One message of a batch: `mk` is the child master key at (`x_pos`, `y_pos`).
+pub struct SignItem<'a> {
+    pub message: BigInt,
+    pub mk: &'a MasterKey2,
+    pub x_pos: BigInt,
+    pub y_pos: BigInt,
+}
+
+/// Sign many messages in one session: both rounds carry every message, so the cost is two
+/// round trips whatever the batch size. Signatures come back in the order of `items`.
+pub fn sign_batch<C: Party1Transport>(
+    client_shim: &C,
+    items: &[SignItem],
+    id: &str,
+) -> Result<Vec<party_one::SignatureRecid>, TssError> {
+    if items.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut states: Vec<SignState> = items
+        .iter()
+        .map(|item| SignState::new(item.message.clone(), item.x_pos.clone(), item.y_pos.clone()))
+        .collect();
+
+    let first_messages: Vec<&party_two::EphKeyGenFirstMsg> =
+        states.iter().map(|s| s.first_message()).collect();
+    let party_one_first_messages: Vec<party_one::EphKeyGenFirstMsg> = match client_shim.postb(
+        &format!("{}/{}/batch/first", SIGN_PATH_PRE, id),
+        &first_messages,
     ) {
-        Ok(s) => s,
-        Err(e) => return Err(anyhow!("ecdsa::get_signature failed failed: {}", e)),
+        Ok(Some(s)) => s,
+        Ok(None) => {
+            return Err(TssError::protocol(
+                "sign batch first message",
+                "empty response",
+            ))
+        }
+        // Server without batch rounds: nothing is open yet, sign input by input
+        Err(e) if e.is_unsupported() => {
+            return items
+                .iter()
+                .map(|item| {
+                    sign(
+                        client_shim,
+                        item.message.clone(),
+                        item.mk,
+                        item.x_pos.clone(),
+                        item.y_pos.clone(),
+                        id,
+                    )
+                })
+                .collect()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    handle_batch_first_messages(&mut states, items, &party_one_first_messages)?;
+    let second_messages: Vec<&SignSecondMsgRequest> =
+        states.iter().filter_map(|s| s.second_message()).collect();
+    let signatures: Vec<party_one::SignatureRecid> = match client_shim.postb(
+        &format!("{}/{}/batch/second", SIGN_PATH_PRE, id),
+        &second_messages,
+    )? {
+        Some(s) => s,
+        None => {
+            return Err(TssError::protocol(
+                "sign batch second message",
+                "empty response",
+            ))
+        }
     };
 
-    Ok(signature)
+    handle_batch_signatures(states, items, signatures)
 }
 
-fn get_signature(
-    client_shim: &ClientShim,
+// Local steps of batch signing, shared by the blocking and the async message flow.
+
+pub(crate) fn handle_batch_first_messages(
+    states: &mut [SignState],
+    items: &[SignItem],
+    party_one_first_messages: &[party_one::EphKeyGenFirstMsg],
+) -> Result<(), TssError> {
+    if party_one_first_messages.len() != states.len() {
+        return Err(TssError::protocol(
+            "sign batch first message",
+            format!(
+                "expected {} messages, got {}",
+                states.len(),
+                party_one_first_messages.len()
+            ),
+        ));
+    }
+
+    for ((state, item), p1_first) in states
+        .iter_mut()
+        .zip(items.iter())
+        .zip(party_one_first_messages.iter())
+    {
+        state.handle_party_one_first_message(item.mk, p1_first)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn handle_batch_signatures(
+    states: Vec<SignState>,
+    items: &[SignItem],
+    signatures: Vec<party_one::SignatureRecid>,
+) -> Result<Vec<party_one::SignatureRecid>, TssError> {
+    if signatures.len() != states.len() {
+        return Err(TssError::protocol(
+            "sign batch second message",
+            format!(
+                "expected {} signatures, got {}",
+                states.len(),
+                signatures.len()
+            ),
+        ));
+    }
+
+    let mut result = Vec::with_capacity(states.len());
+    for ((mut state, item), signature) in states.into_iter().zip(items.iter()).zip(signatures) {
+        state.handle_signature(item.mk, signature)?;
+        result.push(state.into_signature()?);
+    }
+    Ok(result)
+}
+
+/// Round of signing reached by a `SignState`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignRound {
+    /// `first_message` has to reach P1, waiting for its ephemeral key message.
+    FirstMessage,
+    /// `second_message` has to reach P1, waiting for the signature.
+    SecondMessage,
+    Done,
+}
+
+/// Client side of the two-party signing protocol, independent of how messages travel.
+///
+/// 1. send `first_message` to P1,
+/// 2. feed P1's reply to `handle_party_one_first_message`,
+/// 3. send the returned `SignSecondMsgRequest` to P1,
+/// 4. feed the signature to `handle_signature`, which verifies it.
+///
+/// `mk` must be the same child master key in every call. The state is serializable so it
+/// can wait for a reply that arrives out of band.
+#[derive(Serialize, Deserialize)]
+pub struct SignState {
+    round: SignRound,
     message: BigInt,
-    party_two_sign_message: party2::SignMessage,
-    x_pos_child_key: BigInt,
-    y_pos_child_key: BigInt,
-    id: &str,
-) -> Result<party_one::SignatureRecid> {
-    let request: SignSecondMsgRequest = SignSecondMsgRequest {
-        message,
-        party_two_sign_message,
-        x_pos_child_key,
-        y_pos_child_key,
-    };
+    x_pos: BigInt,
+    y_pos: BigInt,
+    eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+    eph_comm_witness: Option<party_two::EphCommWitness>,
+    eph_ec_key_pair_party2: party_two::EphEcKeyPair,
+    second_message: Option<SignSecondMsgRequest>,
+    signature: Option<party_one::SignatureRecid>,
+}
 
-    let signature: party_one::SignatureRecid =
-        match requests::postb(client_shim, &format!("/ecdsa/sign/{}/second", id), &request)? {
-            Some(s) => s,
-            None => return Err(anyhow!("party1 sign second message request failed",)),
+impl SignState {
+    /// Start signing `message` with the child key at (`x_pos`, `y_pos`).
+    pub fn new(message: BigInt, x_pos: BigInt, y_pos: BigInt) -> SignState {
+        let (eph_key_gen_first_message_party_two, eph_comm_witness, eph_ec_key_pair_party2) =
+            MasterKey2::sign_first_message();
+
+        SignState {
+            round: SignRound::FirstMessage,
+            message,
+            x_pos,
+            y_pos,
+            eph_key_gen_first_message_party_two,
+            eph_comm_witness: Some(eph_comm_witness),
+            eph_ec_key_pair_party2,
+            second_message: None,
+            signature: None,
+        }
+    }
+
+    // Continue from an ephemeral key whose first message already reached P1 (presignature)
+    pub(crate) fn from_ephemeral(
+        message: BigInt,
+        x_pos: BigInt,
+        y_pos: BigInt,
+        eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg,
+        eph_comm_witness: party_two::EphCommWitness,
+        eph_ec_key_pair_party2: party_two::EphEcKeyPair,
+    ) -> SignState {
+        SignState {
+            round: SignRound::FirstMessage,
+            message,
+            x_pos,
+            y_pos,
+            eph_key_gen_first_message_party_two,
+            eph_comm_witness: Some(eph_comm_witness),
+            eph_ec_key_pair_party2,
+            second_message: None,
+            signature: None,
+        }
+    }
+
+    pub fn round(&self) -> SignRound {
+        self.round
+    }
+
+    /// Round 1 message for P1.
+    pub fn first_message(&self) -> &party_two::EphKeyGenFirstMsg {
+        &self.eph_key_gen_first_message_party_two
+    }
+
+    /// Take P1's ephemeral key message and build the round 2 message.
+    pub fn handle_party_one_first_message(
+        &mut self,
+        mk: &MasterKey2,
+        sign_party_one_first_message: &party_one::EphKeyGenFirstMsg,
+    ) -> Result<&SignSecondMsgRequest, TssError> {
+        if self.round != SignRound::FirstMessage {
+            return Err(TssError::protocol(
+                "sign first message",
+                format!("unexpected in round {:?}", self.round),
+            ));
+        }
+        let eph_comm_witness = match self.eph_comm_witness.take() {
+            Some(w) => w,
+            None => {
+                return Err(TssError::protocol(
+                    "sign first message",
+                    "ephemeral commitment witness already used",
+                ))
+            }
         };
 
-    Ok(signature)
+        let party_two_sign_message = mk.sign_second_message(
+            &self.eph_ec_key_pair_party2,
+            eph_comm_witness,
+            sign_party_one_first_message,
+            &self.message,
+        );
+
+        self.round = SignRound::SecondMessage;
+        Ok(self.second_message.insert(SignSecondMsgRequest {
+            message: self.message.clone(),
+            party_two_sign_message,
+            x_pos_child_key: self.x_pos.clone(),
+            y_pos_child_key: self.y_pos.clone(),
+        }))
+    }
+
+    /// Round 2 message for P1, once built.
+    pub fn second_message(&self) -> Option<&SignSecondMsgRequest> {
+        self.second_message.as_ref()
+    }
+
+    /// Take the signature from P1 and check it against `mk`'s public key.
+    pub fn handle_signature(
+        &mut self,
+        mk: &MasterKey2,
+        signature: party_one::SignatureRecid,
+    ) -> Result<&party_one::SignatureRecid, TssError> {
+        if self.round != SignRound::SecondMessage {
+            return Err(TssError::protocol(
+                "sign second message",
+                format!("unexpected in round {:?}", self.round),
+            ));
+        }
+
+        // Never hand out a signature P1 could have forged or garbled
+        verify_signature(&signature, &mk.public.q, &self.message)?;
+
+        self.round = SignRound::Done;
+        Ok(self.signature.insert(signature))
+    }
+
+    pub fn into_signature(self) -> Result<party_one::SignatureRecid, TssError> {
+        match self.signature {
+            Some(signature) if self.round == SignRound::Done => Ok(signature),
+            _ => Err(TssError::protocol("sign", "signing is not finished")),
+        }
+    }
+}
+
+/// Check (r, s) against the child public key and message, and that `recid` recovers
+/// that same key.
+fn verify_signature(
+    signature: &party_one::SignatureRecid,
+    pubkey: &GE,
+    message: &BigInt,
+) -> Result<(), TssError> {
+    let q = FE::q();
+    let zero = BigInt::from(0);
+    if signature.r <= zero || signature.r >= q || signature.s <= zero || signature.s >= q {
+        return Err(TssError::verification(
+            "sign signature",
+            "r or s out of range",
+        ));
+    }
+
+    let sig = party_one::Signature {
+        r: signature.r.clone(),
+        s: signature.s.clone(),
+    };
+    if party_one::verify(&sig, pubkey, message).is_err() {
+        return Err(TssError::verification(
+            "sign signature",
+            "signature does not match public key and message",
+        ));
+    }
+
+    // R = (m / s) * G + (r / s) * Q, recid = y parity of R | (R.x >= q) << 1
+    let s_inv: FE = ECScalar::from(&signature.s).invert();
+    let u1: FE = ECScalar::from(message);
+    let u2: FE = ECScalar::from(&signature.r);
+    let r_point: GE = GE::generator() * &(u1 * &s_inv) + *pubkey * &(u2 * &s_inv);
+
+    let (r_x, r_y) = match (r_point.x_coor(), r_point.y_coor()) {
+        (Some(x), Some(y)) => (x, y),
+        _ => {
+            return Err(TssError::verification(
+                "sign signature",
+                "cannot recompute R",
+            ))
+        }
+    };
+    let mut expected_recid: u8 = if r_y.test_bit(0) { 1 } else { 0 };
+    if r_x >= q {
+        expected_recid |= 2;
+    }
+
+    if signature.recid != expected_recid {
+        return Err(TssError::verification(
+            "sign signature",
+            format!(
+                "recovery id {} does not match public key, expected {}",
+                signature.recid, expected_recid
+            ),
+        ));
+    }
+
+    Ok(())
 }
 
 #[no_mangle]
@@ -98,69 +407,61 @@ pub extern "C" fn sign_message(
     c_y_pos: i32,
     c_id: *const c_char,
 ) -> *mut c_char {
-    let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
-
-    let message_hex = match get_str_from_c_char(c_message_le_hex, "message_hex") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+    ffi_guard("sign_message", || {
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let master_key_json = match get_str_from_c_char(c_master_key_json, "master_key_json") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let message_hex = match get_str_from_c_char(c_message_le_hex, "message_hex") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let id = match get_str_from_c_char(c_id, "id") {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let master_key_json = match get_str_from_c_char(c_master_key_json, "master_key_json") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let x: BigInt = BigInt::from(c_x_pos);
-    let y: BigInt = BigInt::from(c_y_pos);
+        let id = match get_str_from_c_char(c_id, "id") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let mk: MasterKey2 = match serde_json::from_str(&master_key_json) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "sign_mk".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let x: BigInt = BigInt::from(c_x_pos);
+        let y: BigInt = BigInt::from(c_y_pos);
 
-    let mk_child: MasterKey2 = mk.get_child(vec![x.clone(), y.clone()]);
-    let message: BigInt = match serde_json::from_str(&message_hex) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E104 {
-                msg: "sign_message".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let mk: MasterKey2 = match serde_json::from_str(&master_key_json) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "sign_mk".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let sig = match sign(&client_shim, message, &mk_child, x, y, &id) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "sig".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let mk_child: MasterKey2 = mk.get_child(vec![x.clone(), y.clone()]);
+        let message: BigInt = match serde_json::from_str(&message_hex) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "sign_message".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
 
-    let signature_json = match serde_json::to_string(&sig) {
-        Ok(share) => share,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "signature_json".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let sig = match sign(&client_shim, message, &mk_child, x, y, &id) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "sig".to_owned(),
+                    e,
+                })
+            }
+        };
 
-    CString::new(signature_json).unwrap().into_raw()
+        ok_to_c_string(&sig, "signature_json")
+    })
 }
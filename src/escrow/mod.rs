@@ -1,39 +1,103 @@
+pub mod threshold;
+
+use curv::arithmetic::traits::Converter;
+use curv::cryptographic_primitives::hashing::hash_sha256::HSha256;
+use curv::cryptographic_primitives::hashing::traits::Hash;
 use curv::elliptic::curves::secp256_k1::{FE, GE};
 use curv::elliptic::curves::traits::ECPoint;
 use curv::elliptic::curves::traits::ECScalar;
 
+use centipede::juggling::segmentation::Msegmentation;
+
 use serde_json;
+use std::fmt;
 use std::fs;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::dto::backup::ClientBackup;
+use crate::utilities::encryption::{self, FileKey};
+use crate::utilities::err_handling::TssError;
+use crate::utilities::secret::REDACTED;
+use crate::utilities::storage::write_atomic;
+
+use self::threshold::{PartialDecryption, ThresholdEscrow};
 
 const ESCROW_SK_FILENAME: &str = "escrow/escrow-sk.json";
+pub const ESCROW_PK_FILENAME: &str = "escrow/escrow-pk.json";
+pub const ESCROW_VSS_FILENAME: &str = "escrow/escrow-vss.json";
 
 pub const SEGMENT_SIZE: usize = 8;
 pub const NUM_SEGMENTS: usize = 32;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Escrow key pair. Backups only need the public key (`load_public_key`); the secret is
+/// needed for recovery alone and should be kept apart from the wallet.
+#[derive(Serialize, Deserialize)]
 pub struct Escrow {
     pub secret: FE,
     pub public: GE,
 }
 
 impl Escrow {
-    pub fn new() -> Escrow {
+    /// Generate a new escrow key pair in memory. Store it with `save`.
+    pub fn generate() -> Escrow {
         let secret: FE = ECScalar::new_random();
         let g: GE = ECPoint::generator();
         let public: GE = g * secret;
-        fs::write(
-            ESCROW_SK_FILENAME,
-            serde_json::to_string(&(secret, public)).unwrap(),
-        )
-        .expect("Unable to save escrow secret!");
 
         Escrow { secret, public }
     }
 
-    pub fn load() -> Escrow {
-        let sec_data = fs::read_to_string(ESCROW_SK_FILENAME).expect("Unable to load wallet!");
-        let (secret, public): (FE, GE) = serde_json::from_str(&sec_data).unwrap();
-        Escrow { secret, public }
+    /// Write the secret, encrypted under `passphrase`, to `sk_path` and the public key to
+    /// `pk_path`. Fails rather than overwrite an existing secret or public key: backups
+    /// encrypted to the old key would become unrecoverable.
+    pub fn save_to(&self, sk_path: &str, pk_path: &str, passphrase: &str) -> Result<(), TssError> {
+        if passphrase.is_empty() {
+            return Err(TssError::Storage(
+                "a passphrase is required to store the escrow secret".to_owned(),
+            ));
+        }
+        for path in &[sk_path, pk_path] {
+            if Path::new(path).exists() {
+                return Err(TssError::Storage(format!(
+                    "{} already exists, refusing to replace the escrow key",
+                    path
+                )));
+            }
+        }
+        let sec_data = Zeroizing::new(serde_json::to_string(&(self.secret, self.public))?);
+        let encrypted = FileKey::new(passphrase)?.encrypt(sec_data.as_bytes())?;
+        write_atomic(sk_path, encrypted.as_bytes(), 0)?;
+        self.export_public_key(pk_path)
+    }
+
+    pub fn save(&self, passphrase: &str) -> Result<(), TssError> {
+        self.save_to(ESCROW_SK_FILENAME, ESCROW_PK_FILENAME, passphrase)
+    }
+
+    /// Read the secret at `sk_path`. Files written before the secret was encrypted are
+    /// still read, without using `passphrase`.
+    pub fn load_from(sk_path: &str, passphrase: &str) -> Result<Escrow, TssError> {
+        let data = Zeroizing::new(fs::read_to_string(sk_path)?);
+        let sec_data = if encryption::is_encrypted(&data) {
+            encryption::decrypt(&data, passphrase)?.1
+        } else {
+            warn!("{} holds an unencrypted escrow secret", sk_path);
+            Zeroizing::new(data.as_bytes().to_vec())
+        };
+        let (secret, public): (FE, GE) = serde_json::from_slice(&sec_data)?;
+        Ok(Escrow { secret, public })
+    }
+
+    pub fn load(passphrase: &str) -> Result<Escrow, TssError> {
+        Escrow::load_from(ESCROW_SK_FILENAME, passphrase)
+    }
+
+    /// Write the public key alone, in the form `load_public_key` reads, for distribution
+    /// to clients.
+    pub fn export_public_key(&self, pk_path: &str) -> Result<(), TssError> {
+        write_atomic(pk_path, serde_json::to_string(&self.public)?.as_bytes(), 0)?;
+        Ok(())
     }
 
     pub fn get_public_key(&self) -> GE {
@@ -45,8 +109,138 @@ impl Escrow {
     }
 }
 
-impl Default for Escrow {
-    fn default() -> Self {
-        Escrow::new()
+/// What a backup is decrypted with: the escrow secret, or partial decryptions of the
+/// backup by enough custodians of a threshold escrow.
+pub enum RecoveryKey {
+    Escrow(Escrow),
+    Custodians {
+        escrow: ThresholdEscrow,
+        partials: Vec<PartialDecryption>,
+    },
+}
+
+impl RecoveryKey {
+    /// Decrypt P2's share from `backup`.
+    pub fn decrypt_backup(&self, backup: &ClientBackup) -> Result<FE, TssError> {
+        match self {
+            RecoveryKey::Escrow(escrow) => {
+                let g: GE = ECPoint::generator();
+                Msegmentation::decrypt(
+                    &backup.encryptions,
+                    &g,
+                    &escrow.secret,
+                    &backup.segment_size,
+                )
+                .map_err(|e| TssError::verification("escrow decryption", format!("{:?}", e)))
+            }
+            RecoveryKey::Custodians { escrow, partials } => escrow.decrypt_backup(backup, partials),
+        }
+    }
+}
+
+/// Parse an escrow public key in the JSON form written by `Escrow::save` (also the form
+/// the FFI takes).
+pub fn parse_public_key(data: &str) -> Result<GE, TssError> {
+    Ok(serde_json::from_str::<GE>(data.trim())?)
+}
+
+/// Read an escrow public key written by `Escrow::save`, or provided by the escrow holder.
+pub fn load_public_key(path: &str) -> Result<GE, TssError> {
+    parse_public_key(&fs::read_to_string(path)?)
+}
+
+/// Short identifier of an escrow public key, recorded in backups so a backup can be
+/// matched with its escrow key.
+pub fn fingerprint(public: &GE) -> String {
+    let hash = HSha256::create_hash_from_slice(&public.pk_to_key_slice());
+    format!("{:0>64}", hash.to_hex())[..16].to_owned()
+}
+
+impl Drop for Escrow {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl fmt::Debug for Escrow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Escrow")
+            .field("secret", &REDACTED)
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::arithmetic::traits::Converter;
+    use curv::elliptic::curves::traits::ECScalar;
+    use std::fs;
+    use std::path::Path;
+
+    use super::{fingerprint, load_public_key, Escrow};
+
+    #[test]
+    fn test_save_never_replaces_secret() {
+        let dir = std::env::temp_dir();
+        let sk_path = dir.join("tss_escrow_test_sk.json");
+        let pk_path = dir.join("tss_escrow_test_pk.json");
+        let (sk_path, pk_path) = (sk_path.to_str().unwrap(), pk_path.to_str().unwrap());
+        let _ = fs::remove_file(sk_path);
+        let _ = fs::remove_file(pk_path);
+
+        let escrow = Escrow::generate();
+        escrow.save_to(sk_path, pk_path, "escrow pass").unwrap();
+        let public = load_public_key(pk_path).unwrap();
+        assert_eq!(fingerprint(&public), fingerprint(&escrow.get_public_key()));
+
+        let err = Escrow::generate()
+            .save_to(sk_path, pk_path, "escrow pass")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), 207);
+        assert_eq!(load_public_key(pk_path).unwrap(), public);
+
+        // an existing public key alone is not replaced either, and no secret is written
+        let _ = fs::remove_file(sk_path);
+        let err = Escrow::generate()
+            .save_to(sk_path, pk_path, "escrow pass")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), 207);
+        assert!(!Path::new(sk_path).exists());
+        assert_eq!(load_public_key(pk_path).unwrap(), public);
+
+        let _ = fs::remove_file(pk_path);
+    }
+
+    #[test]
+    fn test_secret_is_encrypted() {
+        let dir = std::env::temp_dir();
+        let sk_path = dir.join("tss_escrow_enc_test_sk.json");
+        let pk_path = dir.join("tss_escrow_enc_test_pk.json");
+        let (sk_path, pk_path) = (sk_path.to_str().unwrap(), pk_path.to_str().unwrap());
+        let _ = fs::remove_file(sk_path);
+        let _ = fs::remove_file(pk_path);
+
+        let escrow = Escrow::generate();
+        assert!(escrow.save_to(sk_path, pk_path, "").is_err());
+        escrow.save_to(sk_path, pk_path, "escrow pass").unwrap();
+
+        let secret_hex = escrow.get_private_key().to_big_int().to_hex();
+        assert!(!fs::read_to_string(sk_path).unwrap().contains(&secret_hex));
+        assert_eq!(
+            Escrow::load_from(sk_path, "wrong pass")
+                .err()
+                .unwrap()
+                .code(),
+            207
+        );
+        let loaded = Escrow::load_from(sk_path, "escrow pass").unwrap();
+        assert_eq!(loaded.get_private_key(), escrow.get_private_key());
+        assert_eq!(loaded.get_public_key(), escrow.get_public_key());
+
+        let _ = fs::remove_file(sk_path);
+        let _ = fs::remove_file(pk_path);
     }
 }
@@ -0,0 +1,296 @@
+// Threshold escrow: the escrow secret is Feldman-shared among n custodians and never
+// assembled. Each custodian decrypts its part of a backup's ElGamal segments (with a proof
+// against its VSS commitment); any t of these partial decryptions recover P2's share.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use centipede::juggling::proof_system::{Helgamal, Helgamalsegmented};
+use centipede::juggling::segmentation::Msegmentation;
+use curv::cryptographic_primitives::proofs::sigma_ec_ddh::*;
+use curv::cryptographic_primitives::secret_sharing::feldman_vss::VerifiableSS;
+use curv::elliptic::curves::secp256_k1::{FE, GE};
+use curv::elliptic::curves::traits::{ECPoint, ECScalar};
+use curv::BigInt;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::dto::backup::ClientBackup;
+use crate::utilities::encryption::{self, FileKey};
+use crate::utilities::err_handling::TssError;
+use crate::utilities::secret::REDACTED;
+use crate::utilities::storage::write_atomic;
+
+/// Public part of a threshold escrow: the VSS commitments. Backups are encrypted to
+/// `get_public_key`, the commitment to the shared secret.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ThresholdEscrow {
+    pub vss: VerifiableSS<GE>,
+}
+
+/// Share of custodian `index` (1 to n) of the escrow secret.
+#[derive(Serialize, Deserialize)]
+pub struct CustodianShare {
+    pub index: usize,
+    share: FE,
+    pub vss: VerifiableSS<GE>,
+}
+
+/// A custodian's decryption of every segment of one backup: `shares[j] = x_i * E_j`, with
+/// `proofs[j]` showing the same `x_i` is behind the custodian's VSS commitment.
+#[derive(Serialize, Deserialize)]
+pub struct PartialDecryption {
+    pub index: usize,
+    pub shares: Vec<GE>,
+    pub proofs: Vec<ECDDHProof<GE>>,
+}
+
+impl ThresholdEscrow {
+    /// Generate an escrow secret and split it so that any `threshold` of `custodians` can
+    /// recover backups. The secret itself is dropped.
+    pub fn generate(
+        threshold: usize,
+        custodians: usize,
+    ) -> Result<(ThresholdEscrow, Vec<CustodianShare>), TssError> {
+        if threshold == 0 || threshold > custodians {
+            return Err(TssError::InvalidArgument(format!(
+                "invalid threshold {} of {} custodians",
+                threshold, custodians
+            )));
+        }
+        let mut secret: FE = ECScalar::new_random();
+        // `share` takes the polynomial degree, one less than the shares needed
+        let (vss, shares) = VerifiableSS::share(threshold - 1, custodians, &secret);
+        secret.zeroize();
+
+        let custodian_shares = shares
+            .into_iter()
+            .enumerate()
+            .map(|(i, share)| CustodianShare {
+                index: i + 1,
+                share,
+                vss: vss.clone(),
+            })
+            .collect();
+        Ok((ThresholdEscrow { vss }, custodian_shares))
+    }
+
+    pub fn get_public_key(&self) -> GE {
+        self.vss.commitments[0]
+    }
+
+    /// Number of custodians needed to recover.
+    pub fn threshold(&self) -> usize {
+        self.vss.parameters.threshold + 1
+    }
+
+    pub fn custodians(&self) -> usize {
+        self.vss.parameters.share_count
+    }
+
+    /// Write the commitments to `path`, which must not exist yet: backups encrypted to a
+    /// replaced escrow could no longer be recovered.
+    pub fn save_to(&self, path: &str) -> Result<(), TssError> {
+        if Path::new(path).exists() {
+            return Err(TssError::Storage(format!(
+                "{} already exists, refusing to replace the threshold escrow",
+                path
+            )));
+        }
+        write_atomic(path, serde_json::to_string(self)?.as_bytes(), 0)?;
+        Ok(())
+    }
+
+    pub fn load_from(path: &str) -> Result<ThresholdEscrow, TssError> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Recover P2's share from the backup with the partial decryptions of at least
+    /// `threshold` distinct custodians. Every partial decryption used is verified first;
+    /// invalid ones are skipped, so one bad custodian cannot block the others.
+    pub fn decrypt_backup(
+        &self,
+        backup: &ClientBackup,
+        partials: &[PartialDecryption],
+    ) -> Result<FE, TssError> {
+        if let Some(fingerprint) = &backup.escrow_key_fingerprint {
+            if *fingerprint != super::fingerprint(&self.get_public_key()) {
+                return Err(TssError::verification(
+                    "threshold escrow",
+                    format!("backup is encrypted to escrow key {}", fingerprint),
+                ));
+            }
+        }
+
+        let mut used: Vec<&PartialDecryption> = Vec::new();
+        for partial in partials {
+            if used.len() == self.threshold() {
+                break;
+            }
+            if used.iter().any(|p| p.index == partial.index) {
+                continue;
+            }
+            if let Err(e) = self.verify_partial(&backup.encryptions, partial) {
+                warn!("skipping partial decryption: {}", e);
+                continue;
+            }
+            used.push(partial);
+        }
+        if used.len() < self.threshold() {
+            return Err(TssError::verification(
+                "threshold escrow",
+                format!(
+                    "{} of {} custodians needed, got {}",
+                    self.threshold(),
+                    self.custodians(),
+                    used.len()
+                ),
+            ));
+        }
+
+        // Lagrange coefficients take zero based indices
+        let indices: Vec<usize> = used.iter().map(|p| p.index - 1).collect();
+        let coefficients: Vec<FE> = indices
+            .iter()
+            .map(|i| self.vss.map_share_to_new_params(*i, &indices))
+            .collect();
+
+        // x * E_j for every segment. The result is an ElGamal encryption under the secret
+        // key 1, which leaves the search for the segment values to `Msegmentation`.
+        let mut unlocked = Vec::with_capacity(backup.encryptions.DE.len());
+        for (j, segment) in backup.encryptions.DE.iter().enumerate() {
+            let mut x_e = used[0].shares[j] * coefficients[0];
+            for (partial, coefficient) in used.iter().zip(&coefficients).skip(1) {
+                x_e = x_e.add_point(&(partial.shares[j] * *coefficient).get_element());
+            }
+            unlocked.push(Helgamal {
+                D: segment.D,
+                E: x_e,
+            });
+        }
+
+        let g: GE = ECPoint::generator();
+        let one: FE = ECScalar::from(&BigInt::from(1));
+        Msegmentation::decrypt(
+            &Helgamalsegmented { DE: unlocked },
+            &g,
+            &one,
+            &backup.segment_size,
+        )
+        .map_err(|e| TssError::verification("threshold escrow", format!("{:?}", e)))
+    }
+
+    fn verify_partial(
+        &self,
+        encryptions: &Helgamalsegmented,
+        partial: &PartialDecryption,
+    ) -> Result<(), TssError> {
+        let step = format!("partial decryption of custodian {}", partial.index);
+        if partial.index == 0 || partial.index > self.custodians() {
+            return Err(TssError::verification(&step, "unknown custodian"));
+        }
+        if partial.shares.len() != encryptions.DE.len()
+            || partial.proofs.len() != encryptions.DE.len()
+        {
+            return Err(TssError::verification(&step, "wrong number of segments"));
+        }
+
+        let g: GE = ECPoint::generator();
+        let commitment = self.vss.get_point_commitment(partial.index);
+        for ((segment, share), proof) in encryptions
+            .DE
+            .iter()
+            .zip(&partial.shares)
+            .zip(&partial.proofs)
+        {
+            let statement = ECDDHStatement {
+                g1: g,
+                h1: commitment,
+                g2: segment.E,
+                h2: *share,
+            };
+            if proof.verify(&statement).is_err() {
+                return Err(TssError::verification(&step, "invalid proof"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CustodianShare {
+    /// Check the share against the VSS commitments.
+    pub fn verify(&self) -> Result<(), TssError> {
+        self.vss
+            .validate_share(&self.share, self.index)
+            .map_err(|_| {
+                TssError::verification(
+                    "custodian share",
+                    format!("share {} does not match the commitments", self.index),
+                )
+            })
+    }
+
+    /// Decrypt this custodian's part of every segment of `backup`.
+    pub fn partial_decrypt(&self, backup: &ClientBackup) -> PartialDecryption {
+        let g: GE = ECPoint::generator();
+        let commitment = g * self.share;
+        let (shares, proofs) = backup
+            .encryptions
+            .DE
+            .iter()
+            .map(|segment| {
+                let share = segment.E * self.share;
+                let statement = ECDDHStatement {
+                    g1: g,
+                    h1: commitment,
+                    g2: segment.E,
+                    h2: share,
+                };
+                let proof = ECDDHProof::prove(&ECDDHWitness { x: self.share }, &statement);
+                (share, proof)
+            })
+            .unzip();
+
+        PartialDecryption {
+            index: self.index,
+            shares,
+            proofs,
+        }
+    }
+
+    /// Write the share encrypted under the custodian's `passphrase`.
+    pub fn save_to(&self, path: &str, passphrase: &str) -> Result<(), TssError> {
+        if passphrase.is_empty() {
+            return Err(TssError::Storage(
+                "a passphrase is required to store a custodian share".to_owned(),
+            ));
+        }
+        let data = Zeroizing::new(serde_json::to_string(self)?);
+        let encrypted = FileKey::new(passphrase)?.encrypt(data.as_bytes())?;
+        write_atomic(path, encrypted.as_bytes(), 0)?;
+        Ok(())
+    }
+
+    pub fn load_from(path: &str, passphrase: &str) -> Result<CustodianShare, TssError> {
+        let data = fs::read_to_string(path)?;
+        let (_, share_json) = encryption::decrypt(&data, passphrase)?;
+        let share: CustodianShare = serde_json::from_slice(&share_json)?;
+        share.verify()?;
+        Ok(share)
+    }
+}
+
+impl Drop for CustodianShare {
+    fn drop(&mut self) {
+        self.share.zeroize();
+    }
+}
+
+impl fmt::Debug for CustodianShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustodianShare")
+            .field("index", &self.index)
+            .field("share", &REDACTED)
+            .finish()
+    }
+}
@@ -4,37 +4,37 @@ use std::str::FromStr;
 use crate::dto::ecdsa::MKPosDto;
 use crate::dto::ecdsa::PrivateShare;
 use crate::dto::eth::{EthSendTxReqBody, EthSendTxResp, EthTxParamsReqBody, EthTxParamsResp};
-use crate::ecdsa::sign::sign;
+use crate::ecdsa::presign::{sign_with_pool, PresignaturePool, PresignatureStore};
 use crate::eth::transaction::Transaction;
 use crate::eth::utils::pubkey_to_eth_address;
-use crate::utilities::err_handling::{error_to_c_string, ErrorFFIKind};
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind, TssError};
 use crate::utilities::ffi::ffi_utils::{
-    get_addresses_derivation_map_from_raw, get_client_shim_from_raw, get_private_share_from_raw,
+    ffi_guard, get_addresses_derivation_map_from_raw, get_client_shim_from_raw,
+    get_private_share_from_raw, get_str_from_c_char,
 };
-use crate::utilities::requests::{self, ClientShim};
+use crate::utilities::transport::Party1Transport;
 
-use anyhow::{anyhow, Result};
 use curv::arithmetic::traits::Converter;
 use curv::BigInt;
-use hex;
-use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
 use web3::types::{Address, H256};
 use web3::{self, signing::Signature};
 
-pub fn sign_and_send(
+pub fn sign_and_send<C: Party1Transport, S: PresignatureStore>(
     from: &str,
     to: &str,
     eth_value: f64,
-    client_shim: &ClientShim,
+    client_shim: &C,
     private_share: &PrivateShare,
     addresses_derivation_map: &HashMap<String, MKPosDto>,
-) -> Result<H256> {
+    presignatures: &mut S,
+) -> Result<H256, TssError> {
     let pos_mk = match addresses_derivation_map.get(from.to_lowercase().as_str()) {
         Some(pos_mk) => pos_mk,
         None => {
-            return Err(anyhow!(
-                "from address not found in addresses_derivation_map"
+            return Err(TssError::invalid_address(
+                from,
+                "from address not found in addresses_derivation_map",
             ))
         }
     };
@@ -42,7 +42,10 @@ pub fn sign_and_send(
     let pos = pos_mk.pos;
 
     let from_address = pubkey_to_eth_address(mk);
-    let to_address = Address::from_str(to)?;
+    let to_address = match Address::from_str(to) {
+        Ok(a) => a,
+        Err(e) => return Err(TssError::invalid_address(to, e)),
+    };
 
     let tx_params_body = EthTxParamsReqBody {
         from_address,
@@ -51,9 +54,9 @@ pub fn sign_and_send(
     };
 
     let tx_params: EthTxParamsResp =
-        match requests::postb(client_shim, "eth/tx/params", tx_params_body)? {
+        match client_shim.postb_idempotent("eth/tx/params", tx_params_body)? {
             Some(s) => s,
-            None => return Err(anyhow!("get ETH tx params request failed")),
+            None => return Err(TssError::protocol("eth tx params", "empty response")),
         };
 
     let tx = Transaction {
@@ -68,36 +71,61 @@ pub fn sign_and_send(
         max_priority_fee_per_gas: tx_params.max_priority_fee_per_gas,
     };
     let chain_id = tx_params.chain_id;
-    let msg = tx.get_hash(chain_id);
+    let msg = tx.get_hash(chain_id)?;
 
-    let sig = sign(
+    let sig = sign_with_pool(
         client_shim,
-        BigInt::from_hex(&hex::encode(&msg[..])).unwrap(),
+        presignatures,
+        private_share,
+        BigInt::from_bytes(&msg[..]),
         mk,
         BigInt::from(0),
         BigInt::from(pos),
-        &private_share.id,
     )?;
 
-    let r = H256::from_slice(&BigInt::to_bytes(&sig.r));
-    let s = H256::from_slice(&BigInt::to_bytes(&sig.s));
-    let v = sig.recid as u64 + 35 + chain_id * 2;
+    let r = to_h256(&sig.r)?;
+    let s = to_h256(&sig.s)?;
+    let v = to_v(sig.recid, chain_id)?;
     let signature = Signature { r, s, v };
-    let signed = tx.sign(signature, chain_id);
+    let signed = tx.sign(signature, chain_id)?;
 
     let tx_send_body = EthSendTxReqBody {
         raw_tx: signed.raw_transaction,
     };
 
-    let transaction_result: EthSendTxResp =
-        match requests::postb(client_shim, "eth/tx/send", tx_send_body)? {
-            Some(s) => s,
-            None => return Err(anyhow!("send ETH tx request failed")),
-        };
+    let transaction_result: EthSendTxResp = match client_shim.postb("eth/tx/send", tx_send_body)? {
+        Some(s) => s,
+        None => return Err(TssError::protocol("eth tx send", "empty response")),
+    };
 
     Ok(transaction_result.tx_hash)
 }
 
+// EIP-155 `v` only encodes the y parity of R; recid 2 and 3 (R.x >= q) cannot be sent
+fn to_v(recid: u8, chain_id: u64) -> Result<u64, TssError> {
+    if recid > 1 {
+        return Err(TssError::verification(
+            "eth tx signature",
+            format!("recovery id {} cannot be encoded in v", recid),
+        ));
+    }
+    Ok(recid as u64 + 35 + chain_id * 2)
+}
+
+// Signature scalars lose their leading zero bytes as BigInt, pad them back to 32 bytes
+fn to_h256(n: &BigInt) -> Result<H256, TssError> {
+    let bytes = BigInt::to_bytes(n);
+    if bytes.len() > 32 {
+        return Err(TssError::verification(
+            "eth tx signature",
+            "signature scalar longer than 32 bytes",
+        ));
+    }
+    let mut padded = [0u8; 32];
+    padded[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(H256::from(padded))
+}
+
 #[no_mangle]
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn send_eth_tx(
@@ -110,81 +138,65 @@ pub extern "C" fn send_eth_tx(
     c_private_share_json: *const c_char,
     c_addresses_derivation_map: *const c_char,
 ) -> *mut c_char {
-    let raw_from_address = unsafe { CStr::from_ptr(c_from_address) };
-    let from_address = match raw_from_address.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E100 {
-                msg: "from_address".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
-
-    let raw_to_address = unsafe { CStr::from_ptr(c_to_address) };
-    let to_address = match raw_to_address.to_str() {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E100 {
-                msg: "to_address".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+    ffi_guard("send_eth_tx", || {
+        let from_address = match get_str_from_c_char(c_from_address, "from_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E100 {
-                msg: "client_shim".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let to_address = match get_str_from_c_char(c_to_address, "to_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let private_share = match get_private_share_from_raw(c_private_share_json) {
-        Ok(s) => s,
-        Err(e) => return error_to_c_string(e),
-    };
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
 
-    let addresses_derivation_map =
-        match get_addresses_derivation_map_from_raw(c_addresses_derivation_map) {
+        let private_share = match get_private_share_from_raw(c_private_share_json) {
             Ok(s) => s,
             Err(e) => return error_to_c_string(e),
         };
 
-    let tx_hash = match sign_and_send(
-        from_address,
-        to_address,
-        c_amount_eth,
-        &client_shim,
-        &private_share,
-        &addresses_derivation_map,
-    ) {
-        Ok(s) => s,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E103 {
-                msg: "tx_hash".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        let addresses_derivation_map =
+            match get_addresses_derivation_map_from_raw(c_addresses_derivation_map) {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            };
+
+        let tx_hash = match sign_and_send(
+            &from_address,
+            &to_address,
+            c_amount_eth,
+            &client_shim,
+            &private_share,
+            &addresses_derivation_map,
+            &mut PresignaturePool::new(),
+        ) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "tx_hash".to_owned(),
+                    e,
+                })
+            }
+        };
 
-    let tx_hash_json = match serde_json::to_string(&tx_hash) {
-        Ok(tx_resp) => tx_resp,
-        Err(e) => {
-            return error_to_c_string(ErrorFFIKind::E102 {
-                msg: "tx_hash".to_owned(),
-                e: e.to_string(),
-            })
-        }
-    };
+        ok_to_c_string(&tx_hash, "tx_hash")
+    })
+}
 
-    match CString::new(tx_hash_json) {
-        Ok(s) => s.into_raw(),
-        Err(e) => error_to_c_string(ErrorFFIKind::E101 {
-            msg: "tx_hash".to_owned(),
-            e: e.to_string(),
-        }),
+#[cfg(test)]
+mod tests {
+    use super::to_v;
+
+    #[test]
+    fn test_to_v_rejects_high_recid() {
+        assert_eq!(to_v(0, 4).unwrap(), 43);
+        assert_eq!(to_v(1, 4).unwrap(), 44);
+        for recid in 2..4 {
+            assert_eq!(to_v(recid, 4).err().unwrap().code(), 203);
+        }
     }
 }
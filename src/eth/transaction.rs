@@ -1,4 +1,6 @@
 use rlp::RlpStream;
+
+use crate::utilities::err_handling::TssError;
 use web3::{
     signing::{self, Signature},
     types::{AccessList, Address, SignedTransaction, U256, U64},
@@ -123,49 +125,50 @@ impl Transaction {
         }
     }
 
-    fn encode(&self, chain_id: u64, signature: Option<&Signature>) -> Vec<u8> {
+    fn encode(&self, chain_id: u64, signature: Option<&Signature>) -> Result<Vec<u8>, TssError> {
         match self.transaction_type.map(|t| t.as_u64()) {
             Some(LEGACY_TX_ID) | None => {
                 let stream = self.encode_legacy(chain_id, signature);
-                stream.out().to_vec()
+                Ok(stream.out().to_vec())
             }
 
             Some(ACCESSLISTS_TX_ID) => {
                 let tx_id: u8 = ACCESSLISTS_TX_ID as u8;
                 let stream = self.encode_access_list_payload(chain_id, signature);
-                [&[tx_id], stream.as_raw()].concat()
+                Ok([&[tx_id], stream.as_raw()].concat())
             }
 
             Some(EIP1559_TX_ID) => {
                 let tx_id: u8 = EIP1559_TX_ID as u8;
                 let stream = self.encode_eip1559_payload(chain_id, signature);
-                [&[tx_id], stream.as_raw()].concat()
+                Ok([&[tx_id], stream.as_raw()].concat())
             }
 
-            _ => {
-                panic!("Unsupported transaction type");
-            }
+            Some(t) => Err(TssError::protocol(
+                "eth tx params",
+                format!("unsupported transaction type {}", t),
+            )),
         }
     }
 
     /// Sign and return a raw signed transaction.
-    pub fn sign(self, signature: Signature, chain_id: u64) -> SignedTransaction {
-        let hash = self.get_hash(chain_id);
-        let signed = self.encode(chain_id, Some(&signature));
+    pub fn sign(self, signature: Signature, chain_id: u64) -> Result<SignedTransaction, TssError> {
+        let hash = self.get_hash(chain_id)?;
+        let signed = self.encode(chain_id, Some(&signature))?;
         let transaction_hash = signing::keccak256(signed.as_ref()).into();
 
-        SignedTransaction {
+        Ok(SignedTransaction {
             message_hash: hash.into(),
             v: signature.v,
             r: signature.r,
             s: signature.s,
             raw_transaction: signed.into(),
             transaction_hash,
-        }
+        })
     }
 
-    pub fn get_hash(&self, chain_id: u64) -> [u8; 32] {
-        let encoded = self.encode(chain_id, None);
-        signing::keccak256(encoded.as_ref())
+    pub fn get_hash(&self, chain_id: u64) -> Result<[u8; 32], TssError> {
+        let encoded = self.encode(chain_id, None)?;
+        Ok(signing::keccak256(encoded.as_ref()))
     }
 }
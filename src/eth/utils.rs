@@ -1,5 +1,7 @@
-use anyhow::Result;
-use curv::{elliptic::curves::traits::ECPoint, BigInt};
+use curv::{
+    elliptic::curves::{secp256_k1::GE, traits::ECPoint},
+    BigInt,
+};
 use futures::future::try_join_all;
 use kms::ecdsa::two_party::MasterKey2;
 use std::str::FromStr;
@@ -12,14 +14,15 @@ use web3::{
 };
 
 use crate::dto::ecdsa::PrivateShare;
+use crate::utilities::err_handling::{RequestError, TssError};
 
 pub async fn get_all_addresses_balance(
     web3_connection_url: &str,
     last_derived_pos: u32,
     private_share: &PrivateShare,
-) -> Result<Vec<f64>> {
+) -> Result<Vec<f64>, TssError> {
     let web3_connection = establish_web3_connection(web3_connection_url).await?;
-    let addresses = get_all_addresses(last_derived_pos, private_share).unwrap();
+    let addresses = get_all_addresses(last_derived_pos, private_share)?;
     let result: Vec<f64> = try_join_all(
         addresses
             .iter()
@@ -32,7 +35,7 @@ pub async fn get_all_addresses_balance(
 pub fn get_all_addresses(
     last_derived_pos: u32,
     private_share: &PrivateShare,
-) -> Result<Vec<Address>> {
+) -> Result<Vec<Address>, TssError> {
     let init = 0;
     let last_pos = last_derived_pos;
 
@@ -51,7 +54,11 @@ pub fn get_all_addresses(
 }
 
 pub fn pubkey_to_eth_address(mk: &MasterKey2) -> Address {
-    let pub_k = mk.public.q.get_element().serialize_uncompressed();
+    public_key_to_eth_address(&mk.public.q)
+}
+
+pub fn public_key_to_eth_address(q: &GE) -> Address {
+    let pub_k = q.get_element().serialize_uncompressed();
     let hash = keccak256(&pub_k[1..]);
     Address::from_slice(&hash[12..])
 }
@@ -59,15 +66,26 @@ pub fn pubkey_to_eth_address(mk: &MasterKey2) -> Address {
 pub async fn get_balance_in_eth(
     public_address: String,
     web3_connection: &Web3<transports::WebSocket>,
-) -> Result<f64> {
+) -> Result<f64, TssError> {
     let wei_balance = get_balance(public_address, web3_connection).await?;
     Ok(wei_to_eth(wei_balance))
 }
 
-async fn get_balance(public_address: String, web3_connection: &Web3<WebSocket>) -> Result<U256> {
-    let wallet_address = Address::from_str(public_address.as_str())?;
-    let balance = web3_connection.eth().balance(wallet_address, None).await?;
-    Ok(balance)
+async fn get_balance(
+    public_address: String,
+    web3_connection: &Web3<WebSocket>,
+) -> Result<U256, TssError> {
+    let wallet_address = match Address::from_str(public_address.as_str()) {
+        Ok(a) => a,
+        Err(e) => return Err(TssError::invalid_address(&public_address, e)),
+    };
+    match web3_connection.eth().balance(wallet_address, None).await {
+        Ok(balance) => Ok(balance),
+        Err(e) => Err(TssError::Transport(RequestError::Network {
+            path: "eth_getBalance".to_owned(),
+            e: e.to_string(),
+        })),
+    }
 }
 
 pub fn wei_to_eth(wei_val: U256) -> f64 {
@@ -75,7 +93,12 @@ pub fn wei_to_eth(wei_val: U256) -> f64 {
     res / 1_000_000_000_000_000_000.0
 }
 
-pub async fn establish_web3_connection(url: &str) -> Result<Web3<transports::WebSocket>> {
-    let transport = transports::WebSocket::new(url).await?;
-    Ok(Web3::new(transport))
+pub async fn establish_web3_connection(url: &str) -> Result<Web3<transports::WebSocket>, TssError> {
+    match transports::WebSocket::new(url).await {
+        Ok(transport) => Ok(Web3::new(transport)),
+        Err(e) => Err(TssError::Transport(RequestError::Connect {
+            path: url.to_owned(),
+            e: e.to_string(),
+        })),
+    }
 }
@@ -2,14 +2,25 @@
 
 #[macro_use]
 extern crate clap;
+use centipede::juggling::proof_system::Helgamalsegmented;
 use clap::App;
+use curv::arithmetic::traits::Converter;
+use curv::elliptic::curves::secp256_k1::{FE, GE};
+use curv::elliptic::curves::traits::ECScalar;
+use curv::BigInt;
 
+use client::dto::backup::ClientBackup;
+use client::ecdsa::exit::recover_full_private_key;
 use client::escrow;
+use client::escrow::threshold::{CustodianShare, PartialDecryption, ThresholdEscrow};
+use client::utilities::err_handling::TssError;
 use client::utilities::requests::ClientShim;
+use client::utilities::storage::write_atomic;
 use client::wallet::{self, WALLET_FILENAME};
 use floating_duration::TimeFormat;
 use std::collections::HashMap;
 use std::time::Instant;
+use zeroize::{Zeroize, Zeroizing};
 
 fn main() {
     let yaml = load_yaml!("../cli.yml");
@@ -38,6 +49,15 @@ fn main() {
     // );
 
     let network = "testnet".to_string();
+    // Held until exit, so another CLI process cannot change the wallet meanwhile
+    let _wallet_lock = match wallet::Wallet::lock(WALLET_FILENAME) {
+        Ok(lock) => lock,
+        Err(e) => {
+            println!("{}", e);
+            return;
+        }
+    };
+
     if let Some(matches) = matches.subcommand_matches("create-wallet") {
         println!("Network: [{}], Creating wallet", network);
         let coin_type: &str = matches.value_of("coin-type").unwrap();
@@ -48,13 +68,94 @@ fn main() {
         }
         let token: &str = matches.value_of("token").unwrap();
         client_shim.auth_token = Some(token.to_owned());
-        let wallet = wallet::Wallet::new(&client_shim, &network, coin_type);
+        let mut wallet = wallet::Wallet::new(&client_shim, &network, coin_type);
+        let passphrase = read_new_passphrase("New wallet passphrase (empty for none): ");
+        wallet
+            .set_passphrase(passphrase.as_deref().map(|p| p.as_str()))
+            .unwrap();
         wallet.save();
         println!("Network: [{}], Wallet saved to disk", &network);
 
         println!("Network: [{}], Escrow initiated", &network);
+    } else if let Some(matches) = matches.subcommand_matches("escrow-keygen") {
+        if let Some(custodians) = matches.value_of("custodians") {
+            let custodians: usize = custodians.parse().unwrap();
+            let threshold: usize = matches
+                .value_of("threshold")
+                .map(|t| t.parse().unwrap())
+                .unwrap_or(custodians);
+            if let Err(e) = threshold_escrow_keygen(threshold, custodians) {
+                println!("{}", e);
+            }
+            return;
+        }
+
+        let passphrase = match read_new_passphrase("New escrow passphrase: ") {
+            Some(p) => p,
+            None => {
+                println!("The escrow secret cannot be stored without a passphrase");
+                return;
+            }
+        };
+        let escrow = escrow::Escrow::generate();
+        match escrow.save(&passphrase) {
+            Ok(()) => println!(
+                "Escrow key {} saved, public key in {}",
+                escrow::fingerprint(&escrow.get_public_key()),
+                escrow::ESCROW_PK_FILENAME
+            ),
+            Err(e) => println!("{}", e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("escrow-partial") {
+        let share_path = matches.value_of("share").unwrap();
+        let output = matches.value_of("output").unwrap();
+        let backup = match matches.value_of("backup") {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(TssError::from)
+                .and_then(|data| ClientBackup::from_json(&data)),
+            None => wallet::Wallet::load_backup(),
+        };
+        let written = backup.and_then(|backup| {
+            let passphrase = Zeroizing::new(rpassword::prompt_password("Custodian passphrase: ")?);
+            let share = CustodianShare::load_from(share_path, &passphrase)?;
+            let partial = share.partial_decrypt(&backup);
+            write_atomic(output, serde_json::to_string(&partial)?.as_bytes(), 0)?;
+            Ok(share.index)
+        });
+        match written {
+            Ok(index) => println!(
+                "Partial decryption of custodian {} written to {}",
+                index, output
+            ),
+            Err(e) => println!("{}", e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("escrow-export") {
+        let output = matches
+            .value_of("output")
+            .unwrap_or(escrow::ESCROW_PK_FILENAME);
+        let exported = read_escrow_passphrase()
+            .and_then(|passphrase| escrow::Escrow::load(&passphrase))
+            .and_then(|escrow| {
+                escrow.export_public_key(output)?;
+                Ok(escrow::fingerprint(&escrow.get_public_key()))
+            });
+        match exported {
+            Ok(fingerprint) => println!("Escrow public key {} exported to {}", fingerprint, output),
+            Err(e) => println!("{}", e),
+        }
+    } else if let Some(matches) = matches.subcommand_matches("exit") {
+        if let Err(e) = export_full_private_key(matches) {
+            println!("{}", e);
+        }
     } else if let Some(matches) = matches.subcommand_matches("wallet") {
         let mut wallet: wallet::Wallet = wallet::Wallet::load();
+        if wallet.pending_rotation.is_some() {
+            println!("Finishing interrupted rotation");
+            match wallet.finish_rotation(&client_shim, WALLET_FILENAME) {
+                Ok(_) => println!("Rotation confirmed"),
+                Err(e) => println!("Rotation not finished: {}", e),
+            }
+        }
 
         if matches.is_present("new-address") {
             wallet.get_crypto_address();
@@ -62,30 +163,67 @@ fn main() {
         } else if matches.is_present("get-balance") {
             wallet.get_balance();
         } else if matches.is_present("backup") {
+            let y = match load_escrow_public_key(matches, &hm) {
+                Ok(y) => y,
+                Err(e) => {
+                    println!("Cannot load escrow public key: {}", e);
+                    return;
+                }
+            };
             println!("Backup private share pending (it can take some time)...");
             let start = Instant::now();
-            wallet.backup();
+            wallet.backup(&y);
 
             println!(
                 "Backup key saved in escrow (Took: {})",
                 TimeFormat(start.elapsed())
             );
         } else if matches.is_present("verify") {
-            let escrow = escrow::Escrow::load();
+            let y = match load_escrow_public_key(matches, &hm) {
+                Ok(y) => y,
+                Err(e) => {
+                    println!("Cannot load escrow public key: {}", e);
+                    return;
+                }
+            };
 
             println!("verify encrypted backup (it can take some time)...");
 
             let start = Instant::now();
-            wallet.verify_backup(escrow);
+            wallet.verify_backup(&y);
 
             println!(" (Took: {})", TimeFormat(start.elapsed()));
         } else if matches.is_present("restore") {
-            let escrow = escrow::Escrow::load();
+            let recovery_key = match matches.values_of("partial") {
+                Some(paths) => load_custodian_partials(paths),
+                None => read_escrow_passphrase()
+                    .and_then(|passphrase| escrow::Escrow::load(&passphrase))
+                    .map(escrow::RecoveryKey::Escrow),
+            };
+            let recovery_key = match recovery_key {
+                Ok(key) => key,
+                Err(e) => {
+                    println!("Cannot unlock escrow: {}", e);
+                    return;
+                }
+            };
+
+            let passphrase = read_new_passphrase("New wallet passphrase (empty for none): ");
+            // Never replace an encrypted wallet with a plaintext one
+            if passphrase.is_none() && wallet.is_encrypted() {
+                println!("The wallet file is encrypted, a passphrase is required");
+                return;
+            }
 
             println!("backup recovery in process 📲 (it can take some time)...");
 
             let start = Instant::now();
-            wallet::Wallet::recover_and_save_share(escrow, &network, &client_shim);
+            wallet::Wallet::recover_and_save_share(
+                recovery_key,
+                &network,
+                &client_shim,
+                passphrase.as_deref().map(|p| p.as_str()),
+            );
 
             println!(
                 " Backup recovered 💾(Took: {})",
@@ -97,12 +235,42 @@ fn main() {
             let start = Instant::now();
             let token: &str = matches.value_of("token").unwrap();
             client_shim.auth_token = Some(token.to_owned());
-            wallet.rotate(&client_shim, WALLET_FILENAME);
+            wallet.rotate(&client_shim, WALLET_FILENAME).unwrap();
 
             println!(
                 "key rotation complete, (Took: {})",
                 TimeFormat(start.elapsed())
             );
+        } else if matches.is_present("change-passphrase") {
+            let passphrase = read_new_passphrase("New wallet passphrase (empty for none): ");
+            wallet
+                .change_passphrase(passphrase.as_deref().map(|p| p.as_str()), WALLET_FILENAME)
+                .unwrap();
+
+            if wallet.is_encrypted() {
+                println!("Wallet passphrase changed");
+            } else {
+                println!("Wallet saved without encryption");
+            }
+        } else if matches.is_present("presign") {
+            let count: usize = matches.value_of("presign").unwrap().parse().unwrap();
+            let token: &str = matches.value_of("token").unwrap();
+            client_shim.auth_token = Some(token.to_owned());
+
+            let start = Instant::now();
+            let len = wallet.presign(&client_shim, count).unwrap();
+            wallet.save();
+
+            println!(
+                "{} presignatures ready (Took: {})",
+                len,
+                TimeFormat(start.elapsed())
+            );
+            if !wallet.is_encrypted() {
+                println!(
+                    "Warning: the wallet has no passphrase, presignatures are stored in plaintext"
+                );
+            }
         } else if matches.is_present("send") {
             if let Some(matches) = matches.subcommand_matches("send") {
                 let from: &str = matches.value_of("from").unwrap();
@@ -118,12 +286,185 @@ fn main() {
                     to,
                     amount.to_string().parse::<f64>().unwrap(),
                     &client_shim,
+                    WALLET_FILENAME,
                 );
 
-                if wallet.coin_type == "btc" {
-                    wallet.save();
-                }
+                // BTC derives a change address, ETH may use up a presignature
+                wallet.save();
+            }
+        }
+    }
+}
+
+// Escrow public key from `--escrow-key`, the `escrow_public_key` setting, or the file
+// written by `escrow-keygen`, in that order.
+fn load_escrow_public_key(
+    matches: &clap::ArgMatches,
+    settings: &HashMap<String, String>,
+) -> Result<GE, TssError> {
+    let path = matches
+        .value_of("escrow-key")
+        .or_else(|| settings.get("escrow_public_key").map(|s| s.as_str()))
+        .unwrap_or(escrow::ESCROW_PK_FILENAME);
+    escrow::load_public_key(path)
+}
+
+// Split a new escrow secret among `custodians`, each share stored under its custodian's
+// passphrase. Only the commitments and the public key remain in clear.
+fn threshold_escrow_keygen(threshold: usize, custodians: usize) -> Result<(), TssError> {
+    // Checked before anything is written: the public key may belong to an existing escrow
+    if std::path::Path::new(escrow::ESCROW_PK_FILENAME).exists() {
+        return Err(TssError::Storage(format!(
+            "{} already exists, refusing to replace the escrow public key",
+            escrow::ESCROW_PK_FILENAME
+        )));
+    }
+    let (threshold_escrow, shares) = ThresholdEscrow::generate(threshold, custodians)?;
+    threshold_escrow.save_to(escrow::ESCROW_VSS_FILENAME)?;
+
+    for share in &shares {
+        let path = format!("escrow/custodian-{}.json", share.index);
+        loop {
+            let prompt = format!("New passphrase of custodian {}: ", share.index);
+            if let Some(passphrase) = read_new_passphrase(&prompt) {
+                share.save_to(&path, &passphrase)?;
+                break;
             }
+            println!("A custodian share cannot be stored without a passphrase");
+        }
+        println!("Share of custodian {} saved to {}", share.index, path);
+    }
+
+    let public = threshold_escrow.get_public_key();
+    write_atomic(
+        escrow::ESCROW_PK_FILENAME,
+        serde_json::to_string(&public)?.as_bytes(),
+        0,
+    )?;
+    println!(
+        "Threshold escrow key {} ({} of {}) saved, public key in {}",
+        escrow::fingerprint(&public),
+        threshold,
+        custodians,
+        escrow::ESCROW_PK_FILENAME
+    );
+    Ok(())
+}
+
+fn load_custodian_partials(paths: clap::Values) -> Result<escrow::RecoveryKey, TssError> {
+    let escrow = ThresholdEscrow::load_from(escrow::ESCROW_VSS_FILENAME)?;
+    let partials = paths
+        .map(|path| -> Result<PartialDecryption, TssError> {
+            Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(escrow::RecoveryKey::Custodians { escrow, partials })
+}
+
+// Rebuild the full private key of one wallet address from P1's escrowed backup and write it
+// out for import into an ordinary wallet.
+fn export_full_private_key(matches: &clap::ArgMatches) -> Result<(), TssError> {
+    if let Some(path) = matches.value_of("output") {
+        if std::path::Path::new(path).exists() {
+            return Err(TssError::Storage(format!(
+                "{} already exists, refusing to replace it",
+                path
+            )));
+        }
+    }
+    let wallet = wallet::Wallet::load();
+    let position: u32 = matches
+        .value_of("position")
+        .unwrap()
+        .parse()
+        .map_err(|e| TssError::Serialization(format!("invalid position: {}", e)))?;
+
+    let p1_encryptions: Helgamalsegmented = serde_json::from_str(&std::fs::read_to_string(
+        matches.value_of("backup").unwrap(),
+    )?)?;
+    let escrow_key: FE = match matches.value_of("escrow-key") {
+        Some(path) => {
+            let data = Zeroizing::new(std::fs::read_to_string(path)?);
+            let mut key = BigInt::from_hex(data.trim().trim_matches('"'))
+                .map_err(|e| TssError::Serialization(format!("invalid escrow key: {}", e)))?;
+            let escrow_key = ECScalar::from(&key);
+            key.zeroize();
+            escrow_key
+        }
+        None => escrow::Escrow::load(&read_escrow_passphrase()?)?.get_private_key(),
+    };
+
+    let full_key = recover_full_private_key(
+        &wallet.private_share,
+        &p1_encryptions,
+        &escrow_key,
+        BigInt::from(0),
+        BigInt::from(position),
+    )?;
+
+    let format = matches
+        .value_of("format")
+        .unwrap_or(if wallet.coin_type == "eth" {
+            "keystore"
+        } else {
+            "wif"
+        });
+    let exported = match format {
+        "wif" => full_key.to_wif(&wallet.network)?,
+        "hex" => full_key.to_hex(),
+        "keystore" => {
+            let passphrase = match read_new_passphrase("New keystore passphrase: ") {
+                Some(p) => p,
+                None => {
+                    return Err(TssError::Storage(
+                        "a keystore needs a passphrase".to_owned(),
+                    ))
+                }
+            };
+            Zeroizing::new(full_key.to_keystore_v3(&passphrase)?)
+        }
+        _ => {
+            return Err(TssError::Serialization(format!(
+                "unknown key format {}",
+                format
+            )))
+        }
+    };
+
+    if wallet.coin_type == "eth" {
+        println!("Address: {:?}", full_key.eth_address());
+    } else {
+        println!("Address: {}", full_key.btc_address(&wallet.network)?);
+    }
+    match matches.value_of("output") {
+        Some(path) => {
+            // Owner-only, like the wallet file
+            write_atomic(path, exported.as_bytes(), 0)?;
+            println!("Private key ({}) written to {}", format, path);
+        }
+        None => println!("{}", exported.as_str()),
+    }
+    Ok(())
+}
+
+fn read_escrow_passphrase() -> Result<Zeroizing<String>, TssError> {
+    Ok(Zeroizing::new(rpassword::prompt_password(
+        "Escrow passphrase: ",
+    )?))
+}
+
+// Ask twice for a new passphrase; None if left empty.
+fn read_new_passphrase(prompt: &str) -> Option<Zeroizing<String>> {
+    loop {
+        let passphrase = Zeroizing::new(rpassword::prompt_password(prompt).unwrap());
+        if passphrase.is_empty() {
+            return None;
+        }
+        let confirmation =
+            Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ").unwrap());
+        if *passphrase == *confirmation {
+            return Some(passphrase);
         }
+        println!("Passphrases do not match");
     }
 }
@@ -0,0 +1,574 @@
+// In-process Party 1 (server) used to run the two-party protocols without network.
+// It answers the same paths as the TSS server, so it can be handed to any function
+// that is generic over `Party1Transport`.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use curv::cryptographic_primitives::proofs::sigma_dlog::DLogProof;
+use curv::cryptographic_primitives::twoparty::coin_flip_optimal_rounds;
+use curv::cryptographic_primitives::twoparty::dh_key_exchange_variant_with_pok_comm::{
+    CommWitness, EcKeyPair,
+};
+use curv::elliptic::curves::secp256_k1::{FE, GE};
+use curv::elliptic::curves::traits::ECPoint;
+use kms::chain_code::two_party::party1::ChainCode1;
+use kms::ecdsa::two_party::MasterKey1;
+use kms::rotation::two_party::party1::Rotation1;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::{party_one, party_two};
+use serde_json::Value;
+use web3::signing::keccak256;
+use web3::types::{H256, U256};
+
+use crate::dto::ecdsa::SignSecondMsgRequest;
+use crate::dto::eth::{EthSendTxReqBody, EthSendTxResp, EthTxParamsReqBody, EthTxParamsResp};
+use crate::utilities::err_handling::RequestError;
+use crate::utilities::transport::{AsyncParty1Transport, Party1Transport};
+
+#[derive(Default)]
+struct KeygenSession {
+    comm_witness: Option<party_one::CommWitness>,
+    ec_key_pair: Option<party_one::EcKeyPair>,
+    party_one_public_share: Option<GE>,
+    party_two_public_share: Option<GE>,
+    paillier_key_pair: Option<party_one::PaillierKeyPair>,
+    party_one_private: Option<party_one::Party1Private>,
+    cc_comm_witness: Option<CommWitness<GE>>,
+    cc_ec_key_pair: Option<EcKeyPair<GE>>,
+}
+
+// A refusal the server answers with a specific status instead of 400
+#[derive(Debug)]
+struct StatusError {
+    status: u16,
+    msg: String,
+}
+
+impl StatusError {
+    fn rotation_unknown(msg: impl std::fmt::Display) -> StatusError {
+        StatusError {
+            status: 409,
+            msg: format!("mock party1: {}", msg),
+        }
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+#[derive(Default)]
+struct MockState {
+    next_id: u64,
+    keygen: HashMap<String, KeygenSession>,
+    master_keys: HashMap<String, MasterKey1>,
+    sign: HashMap<String, (party_two::EphKeyGenFirstMsg, party_one::EphEcKeyPair)>,
+    sign_batch: HashMap<String, Vec<(party_two::EphKeyGenFirstMsg, party_one::EphEcKeyPair)>>,
+    presign:
+        HashMap<String, HashMap<String, (party_two::EphKeyGenFirstMsg, party_one::EphEcKeyPair)>>,
+    rotate: HashMap<String, (FE, FE)>,
+    rotate_pending: HashMap<String, MasterKey1>,
+    last_derived_pos: HashMap<String, u32>,
+    tamper_signatures: bool,
+    tamper_chain_code: bool,
+    batch_unsupported: bool,
+    unavailable: bool,
+    legacy_rotation: bool,
+}
+
+#[derive(Default)]
+pub struct MockParty1 {
+    state: Mutex<MockState>,
+}
+
+impl MockParty1 {
+    pub fn new() -> MockParty1 {
+        MockParty1::default()
+    }
+
+    /// Party 1 master key of a finished keygen, as the server would store it.
+    pub fn party_one_master_key(&self, id: &str) -> Option<MasterKey1> {
+        let state = self.state.lock().unwrap();
+        let mk = state.master_keys.get(id)?;
+        serde_json::from_value(serde_json::to_value(mk).ok()?).ok()
+    }
+
+    /// Whether an unfinished keygen session `id` is still open.
+    pub fn has_keygen_session(&self, id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.keygen.contains_key(id)
+    }
+
+    /// Presignatures of `id` the server still accepts.
+    pub fn presignature_count(&self, id: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.presign.get(id).map_or(0, |p| p.len())
+    }
+
+    /// Whether a rotation of `id` waits for `rotate/{id}/confirm`.
+    pub fn has_pending_rotation(&self, id: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.rotate_pending.contains_key(id)
+    }
+
+    /// Forget an unconfirmed rotation, as a server expiring it would.
+    pub fn drop_pending_rotation(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.rotate_pending.remove(id);
+    }
+
+    /// Position the server reports on `ecdsa/{id}/recover`.
+    pub fn set_last_derived_pos(&self, id: &str, pos: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.last_derived_pos.insert(id.to_owned(), pos);
+    }
+
+    /// Make `sign/{id}/second` answer with a wrong recovery id, as a faulty server would.
+    pub fn set_tamper_signatures(&self, tamper: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tamper_signatures = tamper;
+    }
+
+    /// Make `keygen/{id}/chaincode/second` reveal a share that does not open its commitment.
+    pub fn set_tamper_chain_code(&self, tamper: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.tamper_chain_code = tamper;
+    }
+
+    /// Answer the `sign/{id}/batch` rounds with 404, as a server without batch signing would.
+    pub fn set_batch_unsupported(&self, unsupported: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.batch_unsupported = unsupported;
+    }
+
+    /// Answer every request with 503, as a server that is down would.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.unavailable = unavailable;
+    }
+
+    /// Rotate the way servers without `rotate/{id}/confirm` do: switch at
+    /// `rotate/{id}/second` and answer the confirm with 404.
+    pub fn set_legacy_rotation(&self, legacy: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.legacy_rotation = legacy;
+    }
+
+    fn dispatch(&self, path: &str, body: Value) -> Result<Value> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut state = self.state.lock().unwrap();
+
+        match segments.as_slice() {
+            ["ecdsa", "keygen", "first"] => keygen_first(&mut state),
+            ["ecdsa", "keygen", id, "second"] => keygen_second(&mut state, id, body),
+            ["ecdsa", "keygen", id, "abort"] => {
+                state.keygen.remove(*id);
+                Ok(Value::Null)
+            }
+            ["ecdsa", "keygen", id, "chaincode", "first"] => chaincode_first(&mut state, id),
+            ["ecdsa", "keygen", id, "chaincode", "second"] => {
+                chaincode_second(&mut state, id, body)
+            }
+            ["ecdsa", "sign", id, "first"] => sign_first(&mut state, id, body),
+            ["ecdsa", "sign", id, "second"] => sign_second(&mut state, id, body),
+            ["ecdsa", "sign", id, "batch", "first"] => sign_batch_first(&mut state, id, body),
+            ["ecdsa", "sign", id, "batch", "second"] => sign_batch_second(&mut state, id, body),
+            ["ecdsa", "sign", id, "presign"] => presign(&mut state, id, body),
+            ["ecdsa", "sign", id, "presign", presign_id, "second"] => {
+                presign_second(&mut state, id, presign_id, body)
+            }
+            ["ecdsa", "rotate", id, "first"] => rotate_first(&mut state, id),
+            ["ecdsa", "rotate", id, "second"] => rotate_second(&mut state, id, body),
+            ["ecdsa", "rotate", id, "confirm"] => rotate_confirm(&mut state, id, body),
+            ["eth", "tx", "params"] => eth_tx_params(body),
+            ["eth", "tx", "send"] => eth_tx_send(body),
+            ["ecdsa", id, "recover"] => Ok(serde_json::to_value(
+                state.last_derived_pos.get(*id).copied().unwrap_or(0),
+            )?),
+            _ => Err(anyhow!("mock party1: unknown path {}", path)),
+        }
+    }
+}
+
+impl Party1Transport for MockParty1 {
+    fn postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize,
+        V: serde::de::DeserializeOwned,
+    {
+        let body = serde_json::to_value(body).map_err(|e| RequestError::Encode {
+            path: path.to_owned(),
+            e: e.to_string(),
+        })?;
+        {
+            let state = self.state.lock().unwrap();
+            if state.unavailable {
+                return Err(RequestError::from_status(path, 503, ""));
+            }
+            if path.contains("/batch/") && state.batch_unsupported {
+                return Err(RequestError::from_status(path, 404, ""));
+            }
+            if path.ends_with("/confirm") && state.legacy_rotation {
+                return Err(RequestError::from_status(path, 404, ""));
+            }
+        }
+        // A refused round looks like the server's 400 reply, unless it carries its own status
+        let resp =
+            self.dispatch(path, body)
+                .map_err(|e| match e.downcast_ref::<StatusError>() {
+                    Some(s) => RequestError::from_status(path, s.status, &s.msg),
+                    None => RequestError::from_status(path, 400, &e.to_string()),
+                })?;
+        match serde_json::from_value(resp) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => Err(RequestError::Protocol {
+                path: path.to_owned(),
+                e: e.to_string(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl AsyncParty1Transport for MockParty1 {
+    async fn a_postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize + Send,
+        V: serde::de::DeserializeOwned,
+    {
+        self.postb(path, body)
+    }
+}
+
+fn keygen_session<'a>(state: &'a mut MockState, id: &str) -> Result<&'a mut KeygenSession> {
+    state
+        .keygen
+        .get_mut(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown keygen session {}", id))
+}
+
+fn keygen_first(state: &mut MockState) -> Result<Value> {
+    state.next_id += 1;
+    let id = format!("mock-{}", state.next_id);
+
+    let (key_gen_first_msg, comm_witness, ec_key_pair) = MasterKey1::key_gen_first_message();
+
+    let session = KeygenSession {
+        party_one_public_share: Some(comm_witness.public_share),
+        comm_witness: Some(comm_witness),
+        ec_key_pair: Some(ec_key_pair),
+        ..KeygenSession::default()
+    };
+    state.keygen.insert(id.clone(), session);
+
+    Ok(serde_json::to_value((id, key_gen_first_msg))?)
+}
+
+fn keygen_second(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let d_log_proof: DLogProof<GE> = serde_json::from_value(body)?;
+    let session = keygen_session(state, id)?;
+
+    let comm_witness = session
+        .comm_witness
+        .take()
+        .ok_or_else(|| anyhow!("mock party1: keygen first message missing"))?;
+    let ec_key_pair = session
+        .ec_key_pair
+        .as_ref()
+        .ok_or_else(|| anyhow!("mock party1: keygen first message missing"))?;
+
+    let (kg_party_one_second_message, paillier_key_pair, party_one_private) =
+        MasterKey1::key_gen_second_message(comm_witness, ec_key_pair, &d_log_proof);
+
+    session.party_two_public_share = Some(d_log_proof.pk);
+    session.paillier_key_pair = Some(paillier_key_pair);
+    session.party_one_private = Some(party_one_private);
+
+    Ok(serde_json::to_value(kg_party_one_second_message)?)
+}
+
+fn chaincode_first(state: &mut MockState, id: &str) -> Result<Value> {
+    let session = keygen_session(state, id)?;
+
+    let (cc_party_one_first_message, cc_comm_witness, cc_ec_key_pair) =
+        ChainCode1::chain_code_first_message();
+
+    session.cc_comm_witness = Some(cc_comm_witness);
+    session.cc_ec_key_pair = Some(cc_ec_key_pair);
+
+    Ok(serde_json::to_value(cc_party_one_first_message)?)
+}
+
+fn chaincode_second(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let d_log_proof: DLogProof<GE> = serde_json::from_value(body)?;
+    let mut session = state
+        .keygen
+        .remove(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown keygen session {}", id))?;
+
+    let missing = || anyhow!("mock party1: keygen session {} is incomplete", id);
+    let cc_comm_witness = session.cc_comm_witness.take().ok_or_else(missing)?;
+    let cc_ec_key_pair = session.cc_ec_key_pair.take().ok_or_else(missing)?;
+
+    let mut cc_party_one_second_message =
+        ChainCode1::chain_code_second_message(cc_comm_witness, &d_log_proof);
+    let chain_code = ChainCode1::compute_chain_code(&cc_ec_key_pair, &d_log_proof.pk).chain_code;
+
+    let master_key = MasterKey1::set_master_key(
+        &chain_code,
+        session.party_one_private.take().ok_or_else(missing)?,
+        &session.party_one_public_share.ok_or_else(missing)?,
+        &session.party_two_public_share.ok_or_else(missing)?,
+        session.paillier_key_pair.take().ok_or_else(missing)?,
+    );
+    state.master_keys.insert(id.to_owned(), master_key);
+    if state.tamper_chain_code {
+        cc_party_one_second_message.comm_witness.public_share = GE::generator();
+    }
+
+    Ok(serde_json::to_value(cc_party_one_second_message)?)
+}
+
+fn sign_first(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    if !state.master_keys.contains_key(id) {
+        return Err(anyhow!("mock party1: unknown master key {}", id));
+    }
+    let eph_key_gen_first_message_party_two: party_two::EphKeyGenFirstMsg =
+        serde_json::from_value(body)?;
+
+    let (sign_party_one_first_message, eph_ec_key_pair_party1) = MasterKey1::sign_first_message();
+    state.sign.insert(
+        id.to_owned(),
+        (eph_key_gen_first_message_party_two, eph_ec_key_pair_party1),
+    );
+
+    Ok(serde_json::to_value(sign_party_one_first_message)?)
+}
+
+fn sign_second(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let request: SignSecondMsgRequest = serde_json::from_value(body)?;
+    let (eph_key_gen_first_message_party_two, eph_ec_key_pair_party1) = state
+        .sign
+        .remove(id)
+        .ok_or_else(|| anyhow!("mock party1: sign first message missing for {}", id))?;
+    let master_key = state
+        .master_keys
+        .get(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown master key {}", id))?;
+
+    let child_master_key =
+        master_key.get_child(vec![request.x_pos_child_key, request.y_pos_child_key]);
+    let mut signature = child_master_key
+        .sign_second_message(
+            &request.party_two_sign_message,
+            &eph_key_gen_first_message_party_two,
+            &eph_ec_key_pair_party1,
+            &request.message,
+        )
+        .map_err(|_| anyhow!("mock party1: sign second message rejected"))?;
+    if state.tamper_signatures {
+        signature.recid ^= 1;
+    }
+
+    Ok(serde_json::to_value(signature)?)
+}
+
+fn sign_batch_first(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    if !state.master_keys.contains_key(id) {
+        return Err(anyhow!("mock party1: unknown master key {}", id));
+    }
+    let party_two_first_messages: Vec<party_two::EphKeyGenFirstMsg> = serde_json::from_value(body)?;
+
+    let mut replies = Vec::new();
+    let mut session = Vec::new();
+    for party_two_first_message in party_two_first_messages {
+        let (sign_party_one_first_message, eph_ec_key_pair_party1) =
+            MasterKey1::sign_first_message();
+        replies.push(sign_party_one_first_message);
+        session.push((party_two_first_message, eph_ec_key_pair_party1));
+    }
+    state.sign_batch.insert(id.to_owned(), session);
+
+    Ok(serde_json::to_value(replies)?)
+}
+
+fn sign_batch_second(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let requests: Vec<SignSecondMsgRequest> = serde_json::from_value(body)?;
+    let session = state
+        .sign_batch
+        .remove(id)
+        .ok_or_else(|| anyhow!("mock party1: sign batch first message missing for {}", id))?;
+    if session.len() != requests.len() {
+        return Err(anyhow!("mock party1: sign batch size mismatch"));
+    }
+    let master_key = state
+        .master_keys
+        .get(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown master key {}", id))?;
+
+    let mut signatures = Vec::new();
+    for (request, (eph_key_gen_first_message_party_two, eph_ec_key_pair_party1)) in
+        requests.iter().zip(session.iter())
+    {
+        let child_master_key = master_key.get_child(vec![
+            request.x_pos_child_key.clone(),
+            request.y_pos_child_key.clone(),
+        ]);
+        let signature = child_master_key
+            .sign_second_message(
+                &request.party_two_sign_message,
+                eph_key_gen_first_message_party_two,
+                eph_ec_key_pair_party1,
+                &request.message,
+            )
+            .map_err(|_| anyhow!("mock party1: sign batch second message rejected"))?;
+        signatures.push(signature);
+    }
+
+    Ok(serde_json::to_value(signatures)?)
+}
+
+fn presign(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    if !state.master_keys.contains_key(id) {
+        return Err(anyhow!("mock party1: unknown master key {}", id));
+    }
+    let party_two_first_messages: Vec<party_two::EphKeyGenFirstMsg> = serde_json::from_value(body)?;
+
+    let mut replies = Vec::new();
+    for party_two_first_message in party_two_first_messages {
+        state.next_id += 1;
+        let presign_id = format!("presign-{}", state.next_id);
+        let (sign_party_one_first_message, eph_ec_key_pair_party1) =
+            MasterKey1::sign_first_message();
+        state.presign.entry(id.to_owned()).or_default().insert(
+            presign_id.clone(),
+            (party_two_first_message, eph_ec_key_pair_party1),
+        );
+        replies.push((presign_id, sign_party_one_first_message));
+    }
+
+    Ok(serde_json::to_value(replies)?)
+}
+
+fn presign_second(state: &mut MockState, id: &str, presign_id: &str, body: Value) -> Result<Value> {
+    let request: SignSecondMsgRequest = serde_json::from_value(body)?;
+    // One-time use: the presignature is gone whether or not signing succeeds
+    let (eph_key_gen_first_message_party_two, eph_ec_key_pair_party1) = state
+        .presign
+        .get_mut(id)
+        .and_then(|p| p.remove(presign_id))
+        .ok_or_else(|| anyhow!("mock party1: unknown presignature {}", presign_id))?;
+    let master_key = state
+        .master_keys
+        .get(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown master key {}", id))?;
+
+    let child_master_key =
+        master_key.get_child(vec![request.x_pos_child_key, request.y_pos_child_key]);
+    let signature = child_master_key
+        .sign_second_message(
+            &request.party_two_sign_message,
+            &eph_key_gen_first_message_party_two,
+            &eph_ec_key_pair_party1,
+            &request.message,
+        )
+        .map_err(|_| anyhow!("mock party1: presign second message rejected"))?;
+
+    Ok(serde_json::to_value(signature)?)
+}
+
+fn rotate_first(state: &mut MockState, id: &str) -> Result<Value> {
+    if !state.master_keys.contains_key(id) {
+        return Err(anyhow!("mock party1: unknown master key {}", id));
+    }
+    let (coin_flip_party1_first_message, m1, r1) = Rotation1::key_rotate_first_message();
+    state.rotate.insert(id.to_owned(), (m1, r1));
+
+    Ok(serde_json::to_value(coin_flip_party1_first_message)?)
+}
+
+fn rotate_second(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let coin_flip_party2_first_message: coin_flip_optimal_rounds::Party2FirstMessage<GE> =
+        serde_json::from_value(body)?;
+    let (m1, r1) = state
+        .rotate
+        .remove(id)
+        .ok_or_else(|| anyhow!("mock party1: rotate first message missing for {}", id))?;
+    let master_key = state
+        .master_keys
+        .get(id)
+        .ok_or_else(|| anyhow!("mock party1: unknown master key {}", id))?;
+
+    let (coin_flip_party1_second_message, random1) =
+        Rotation1::key_rotate_second_message(&coin_flip_party2_first_message, &m1, &r1);
+    let (rotation_party1_first_message, master_key_rotated) =
+        master_key.rotation_first_message(&random1);
+    if state.legacy_rotation {
+        state.master_keys.insert(id.to_owned(), master_key_rotated);
+        state.presign.remove(id);
+    } else {
+        // The current key stays in use until the client confirms
+        state
+            .rotate_pending
+            .insert(id.to_owned(), master_key_rotated);
+    }
+
+    Ok(serde_json::to_value((
+        coin_flip_party1_second_message,
+        rotation_party1_first_message,
+    ))?)
+}
+
+fn rotate_confirm(state: &mut MockState, id: &str, body: Value) -> Result<Value> {
+    let party_two_public_share: GE = serde_json::from_value(body)?;
+    match state.rotate_pending.remove(id) {
+        Some(master_key) if master_key.public.p2 == party_two_public_share => {
+            state.master_keys.insert(id.to_owned(), master_key);
+            // Ephemeral keys agreed for the old shares must not be used with the new ones
+            state.presign.remove(id);
+            Ok(Value::Null)
+        }
+        Some(master_key) => {
+            state.rotate_pending.insert(id.to_owned(), master_key);
+            Err(StatusError::rotation_unknown("confirm does not match pending rotation").into())
+        }
+        // Repeated confirm of the rotation already in use
+        None => match state.master_keys.get(id) {
+            Some(master_key) if master_key.public.p2 == party_two_public_share => Ok(Value::Null),
+            _ => {
+                Err(StatusError::rotation_unknown(format!("no pending rotation for {}", id)).into())
+            }
+        },
+    }
+}
+
+// Parameters of a legacy transfer on Rinkeby
+fn eth_tx_params(body: Value) -> Result<Value> {
+    let req: EthTxParamsReqBody = serde_json::from_value(body)?;
+    let gwei = U256::from(1_000_000_000u64);
+    Ok(serde_json::to_value(EthTxParamsResp {
+        to: Some(req.to_address),
+        nonce: U256::zero(),
+        gas: U256::from(21_000u64),
+        gas_price: gwei,
+        value: U256::from((req.eth_value * 1e9) as u64) * gwei,
+        data: Vec::new(),
+        transaction_type: None,
+        access_list: Vec::new(),
+        max_priority_fee_per_gas: U256::zero(),
+        chain_id: 4,
+    })?)
+}
+
+fn eth_tx_send(body: Value) -> Result<Value> {
+    let req: EthSendTxReqBody = serde_json::from_value(body)?;
+    Ok(serde_json::to_value(EthSendTxResp {
+        tx_hash: H256::from(keccak256(&req.raw_tx.0)),
+    })?)
+}
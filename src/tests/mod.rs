@@ -1,4 +1,5 @@
 pub mod common;
+pub mod mock_party1;
 
 #[cfg(test)]
 mod ecdsa_test_suite {
@@ -38,6 +39,8 @@ mod ecdsa_test_suite {
         let private_share = w.private_share;
         let (old_x1, old_x2) = get_coordinates_of_2_mk(&private_share);
         let old_paillier_x = get_coordinate_of_p2_private(&private_share);
+        let old_q = private_share.master_key.public.q;
+        let old_chain_code = private_share.master_key.chain_code.clone();
 
         // Act
         let rotated_private_share = rotate_private_share(private_share, &client_shim).unwrap();
@@ -47,6 +50,8 @@ mod ecdsa_test_suite {
         assert_ne!(new_x1, old_x1);
         assert_ne!(new_x2, old_x2);
         assert_ne!(old_paillier_x, new_paillier_x);
+        assert_eq!(rotated_private_share.master_key.public.q, old_q);
+        assert_eq!(rotated_private_share.master_key.chain_code, old_chain_code);
     }
 }
 
@@ -132,7 +137,7 @@ mod btc_test_suite {
             return;
         }
         // Act
-        w.rotate(&client_shim, w_file);
+        w.rotate(&client_shim, w_file).unwrap();
         let mut new_w = Wallet::load_from(w_file);
         let to_send = SENT_BTC;
         let txid = new_w.send(
@@ -140,6 +145,7 @@ mod btc_test_suite {
             "tb1qz4lma0u0xyepgkzlsegxfxw7e65ue7azhkck5m", // to address in our btc_w
             to_send,
             &client_shim,
+            w_file,
         );
         print_tx_hash(&txid);
         new_w.save_to(w_file);
@@ -224,7 +230,7 @@ mod eth_test_suite {
         }
 
         // Act
-        w.rotate(&client_shim, w_file);
+        w.rotate(&client_shim, w_file).unwrap();
         let mut new_w = Wallet::load_from(w_file);
         let to_send = SENT_ETH;
         let txid = new_w.send(
@@ -232,6 +238,7 @@ mod eth_test_suite {
             TO_ADDRESS,   // to address in eth_w
             to_send,
             &client_shim,
+            w_file,
         );
         print_tx_hash(&txid);
         assert!(!txid.is_empty());
@@ -245,3 +252,671 @@ mod eth_test_suite {
         true
     }
 }
+
+#[cfg(test)]
+mod mock_test_suite {
+    use centipede::juggling::proof_system::{Helgamalsegmented, Proof};
+    use centipede::juggling::segmentation::Msegmentation;
+    use curv::arithmetic::traits::Converter;
+    use curv::elliptic::curves::secp256_k1::GE;
+    use curv::elliptic::curves::traits::ECPoint;
+    use curv::BigInt;
+    use kms::ecdsa::two_party::{MasterKey2, Party2Public};
+    use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_one;
+
+    use crate::{
+        btc::utils::to_bitcoin_address,
+        dto::{
+            backup::{ClientBackup, CLIENT_BACKUP_VERSION},
+            ecdsa::PrivateShare,
+        },
+        ecdsa::{
+            a_get_private_share, a_rotate_private_share, a_sign,
+            exit::recover_full_private_key,
+            get_private_share,
+            keygen::{KeygenRound, KeygenState},
+            presign::{PresignaturePool, PresignatureStore},
+            recover::{backup_client_mk, verify_client_backup},
+            rotate::{begin_rotation, confirm_rotation, rotation_result},
+            rotate_private_share, sign,
+            sign::{SignRound, SignState},
+            sign_batch, sign_with_pool, SignItem,
+        },
+        escrow::{self, threshold::ThresholdEscrow, Escrow},
+        eth::utils::pubkey_to_eth_address,
+        tests::mock_party1::MockParty1,
+        utilities::{derive_new_key, err_handling::TssError, transport::Party1Transport},
+        wallet::Wallet,
+    };
+
+    const MESSAGE_HEX: &str = "7e59998584f83454a4095c90006b277c31ec7b447fee44f88bf57f10edf5ab14";
+
+    fn sign_and_verify(mock: &MockParty1, private_share: &PrivateShare, pos: u32) {
+        let x_pos = BigInt::from(0);
+        let y_pos = BigInt::from(pos);
+        let child_mk = private_share
+            .master_key
+            .get_child(vec![x_pos.clone(), y_pos.clone()]);
+        let message = BigInt::from_hex(MESSAGE_HEX).unwrap();
+
+        let sig = sign(
+            mock,
+            message.clone(),
+            &child_mk,
+            x_pos,
+            y_pos,
+            &private_share.id,
+        )
+        .unwrap();
+
+        let signature = party_one::Signature { r: sig.r, s: sig.s };
+        assert!(party_one::verify(&signature, &child_mk.public.q, &message).is_ok());
+    }
+
+    #[test]
+    fn test_keygen_resume_offline() {
+        let mock = MockParty1::new();
+        let mut state = KeygenState::new();
+        state.step(&mock).unwrap();
+        state.step(&mock).unwrap();
+        assert_eq!(state.round(), KeygenRound::SecondMessage);
+
+        // Persist between rounds, as across an app restart
+        let saved = serde_json::to_string(&state).unwrap();
+        let mut state: KeygenState = serde_json::from_str(&saved).unwrap();
+        state.run(&mock).unwrap();
+
+        let private_share = state.into_private_share().unwrap();
+        let mk1 = mock.party_one_master_key(&private_share.id).unwrap();
+        assert_eq!(mk1.public.q, private_share.master_key.public.q);
+        sign_and_verify(&mock, &private_share, 1);
+    }
+
+    #[test]
+    fn test_keygen_abort_offline() {
+        let mock = MockParty1::new();
+        let mut state = KeygenState::new();
+        state.step(&mock).unwrap();
+        let id = state.id().unwrap().to_owned();
+        assert!(mock.has_keygen_session(&id));
+
+        state.abort(&mock).unwrap();
+        assert!(!mock.has_keygen_session(&id));
+    }
+
+    #[test]
+    fn test_keygen_rejects_bad_chain_code_proof_offline() {
+        let mock = MockParty1::new();
+        mock.set_tamper_chain_code(true);
+
+        let err = get_private_share(&mock).unwrap_err();
+        assert_eq!(err.code(), 203);
+        assert!(err.to_string().contains("keygen chaincode second message"));
+    }
+
+    #[test]
+    fn test_sign_state_machine_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let child_mk = private_share
+            .master_key
+            .get_child(vec![BigInt::from(0), BigInt::from(2)]);
+        let message = BigInt::from_hex(MESSAGE_HEX).unwrap();
+        let id = &private_share.id;
+
+        let mut state = SignState::new(message.clone(), BigInt::from(0), BigInt::from(2));
+        let p1_first: party_one::EphKeyGenFirstMsg = mock
+            .postb(&format!("ecdsa/sign/{}/first", id), state.first_message())
+            .unwrap()
+            .unwrap();
+
+        // The reply may arrive much later, e.g. over a push notification
+        let saved = serde_json::to_string(&state).unwrap();
+        let mut state: SignState = serde_json::from_str(&saved).unwrap();
+
+        let second = state
+            .handle_party_one_first_message(&child_mk, &p1_first)
+            .unwrap();
+        let sig: party_one::SignatureRecid = mock
+            .postb(&format!("ecdsa/sign/{}/second", id), second)
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.round(), SignRound::SecondMessage);
+
+        state.handle_signature(&child_mk, sig).unwrap();
+        let sig = state.into_signature().unwrap();
+        let signature = party_one::Signature { r: sig.r, s: sig.s };
+        assert!(party_one::verify(&signature, &child_mk.public.q, &message).is_ok());
+    }
+
+    #[test]
+    fn test_sign_batch_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+
+        let child_mks: Vec<MasterKey2> = (1..=3)
+            .map(|pos| {
+                private_share
+                    .master_key
+                    .get_child(vec![BigInt::from(0), BigInt::from(pos)])
+            })
+            .collect();
+        let items: Vec<SignItem> = child_mks
+            .iter()
+            .enumerate()
+            .map(|(i, mk)| SignItem {
+                message: BigInt::from_hex(MESSAGE_HEX).unwrap() + BigInt::from(i as u32),
+                mk,
+                x_pos: BigInt::from(0),
+                y_pos: BigInt::from(i as u32 + 1),
+            })
+            .collect();
+
+        let signatures = sign_batch(&mock, &items, &private_share.id).unwrap();
+        assert_eq!(signatures.len(), items.len());
+        for (item, sig) in items.iter().zip(signatures) {
+            let signature = party_one::Signature { r: sig.r, s: sig.s };
+            assert!(party_one::verify(&signature, &item.mk.public.q, &item.message).is_ok());
+        }
+
+        // A server without batch rounds is served input by input
+        mock.set_batch_unsupported(true);
+        let signatures = sign_batch(&mock, &items, &private_share.id).unwrap();
+        assert_eq!(signatures.len(), items.len());
+        for (item, sig) in items.iter().zip(signatures) {
+            let signature = party_one::Signature { r: sig.r, s: sig.s };
+            assert!(party_one::verify(&signature, &item.mk.public.q, &item.message).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_presign_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let x_pos = BigInt::from(0);
+        let y_pos = BigInt::from(1);
+        let child_mk = private_share
+            .master_key
+            .get_child(vec![x_pos.clone(), y_pos.clone()]);
+        let message = BigInt::from_hex(MESSAGE_HEX).unwrap();
+
+        let mut pool = PresignaturePool::new();
+        assert_eq!(pool.refill(&mock, &private_share, 3).unwrap(), 3);
+        assert_eq!(mock.presignature_count(&private_share.id), 3);
+
+        // The pool is persisted with the wallet
+        let saved = serde_json::to_string(&pool).unwrap();
+        let mut pool: PresignaturePool = serde_json::from_str(&saved).unwrap();
+
+        // A presignature whose removal cannot be stored is never used
+        struct FailingStore<'a>(&'a mut PresignaturePool);
+        impl PresignatureStore for FailingStore<'_> {
+            fn pool(&mut self) -> &mut PresignaturePool {
+                self.0
+            }
+
+            fn persist(&mut self) -> Result<(), TssError> {
+                Err(TssError::Storage("disk full".to_owned()))
+            }
+        }
+        let err = sign_with_pool(
+            &mock,
+            &mut FailingStore(&mut pool),
+            &private_share,
+            message.clone(),
+            &child_mk,
+            x_pos.clone(),
+            y_pos.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, TssError::Storage(_)));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(mock.presignature_count(&private_share.id), 3);
+
+        let sig = sign_with_pool(
+            &mock,
+            &mut pool,
+            &private_share,
+            message.clone(),
+            &child_mk,
+            x_pos,
+            y_pos,
+        )
+        .unwrap();
+        let signature = party_one::Signature { r: sig.r, s: sig.s };
+        assert!(party_one::verify(&signature, &child_mk.public.q, &message).is_ok());
+        assert_eq!(pool.len(), 1);
+        assert_eq!(mock.presignature_count(&private_share.id), 2);
+
+        // Rotation invalidates what is left, on both sides
+        let rotated_private_share = rotate_private_share(private_share, &mock).unwrap();
+        assert_eq!(mock.presignature_count(&rotated_private_share.id), 0);
+        assert!(pool.take(&rotated_private_share).is_none());
+    }
+
+    #[test]
+    fn test_sign_rejects_bad_signature_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        mock.set_tamper_signatures(true);
+
+        let child_mk = private_share
+            .master_key
+            .get_child(vec![BigInt::from(0), BigInt::from(1)]);
+        let err = sign(
+            &mock,
+            BigInt::from_hex(MESSAGE_HEX).unwrap(),
+            &child_mk,
+            BigInt::from(0),
+            BigInt::from(1),
+            &private_share.id,
+        )
+        .unwrap_err();
+        assert_eq!(err.code(), 203);
+    }
+
+    #[test]
+    fn test_keygen_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let mk1 = mock.party_one_master_key(&private_share.id).unwrap();
+
+        assert_eq!(private_share.master_key.public.q, mk1.public.q);
+        assert_eq!(private_share.master_key.chain_code, mk1.chain_code);
+    }
+
+    #[test]
+    fn test_sign_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+
+        sign_and_verify(&mock, &private_share, 1);
+    }
+
+    #[test]
+    fn test_rotate_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let old_q = private_share.master_key.public.q;
+        let old_p2 = private_share.master_key.public.p2;
+        let private_share_copy: PrivateShare =
+            serde_json::from_value(serde_json::to_value(&private_share).unwrap()).unwrap();
+
+        let rotated_private_share = rotate_private_share(private_share, &mock).unwrap();
+
+        assert_eq!(rotated_private_share.master_key.public.q, old_q);
+        assert_ne!(rotated_private_share.master_key.public.p2, old_p2);
+        for pos in 0..5 {
+            let (_, mk) = derive_new_key(&private_share_copy, pos);
+            let (_, rotated_mk) = derive_new_key(&rotated_private_share, pos);
+            assert_eq!(rotated_mk.public.q, mk.public.q);
+        }
+        sign_and_verify(&mock, &rotated_private_share, 1);
+    }
+
+    #[test]
+    fn test_rotate_keeps_unconfirmed_share_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+
+        // The confirm is lost: the rotated share comes back with the error
+        let rotated = begin_rotation(&private_share, &mock).unwrap();
+        mock.set_unavailable(true);
+        let confirmed = confirm_rotation(&mock, &rotated);
+        let err = rotation_result(confirmed, rotated).unwrap_err();
+        mock.set_unavailable(false);
+        assert_eq!(err.error.code(), 200);
+        let pending = err.pending.unwrap();
+        assert!(confirm_rotation(&mock, &pending).unwrap());
+        sign_and_verify(&mock, &pending, 1);
+
+        // A server without the confirm endpoint switched on its own
+        mock.set_legacy_rotation(true);
+        let rotated = rotate_private_share(pending, &mock).unwrap();
+        assert!(!mock.has_pending_rotation(&rotated.id));
+        sign_and_verify(&mock, &rotated, 1);
+    }
+
+    #[test]
+    fn test_rotate_two_phase_offline() {
+        let wallet_file = std::env::temp_dir().join("tss_rotate_two_phase_w.json");
+        let wallet_file = wallet_file.to_str().unwrap();
+        let mock = MockParty1::new();
+        let mut w = Wallet::new(&mock, "testnet", "eth");
+        let old_q = w.private_share.master_key.public.q;
+
+        // Crash after P1 answered but before confirming
+        let rotated = begin_rotation(&w.private_share, &mock).unwrap();
+        w.pending_rotation = Some(rotated);
+        w.save_to(wallet_file);
+        assert!(mock.has_pending_rotation(&w.id));
+        sign_and_verify(&mock, &w.private_share, 1);
+
+        let mut w = Wallet::load_from(wallet_file);
+        assert!(w.finish_rotation(&mock, wallet_file).unwrap());
+        assert!(w.pending_rotation.is_none());
+        // Versions holding the old share are gone
+        assert!(std::fs::metadata(format!("{}.1", wallet_file)).is_err());
+        assert!(!mock.has_pending_rotation(&w.id));
+        assert_eq!(w.private_share.master_key.public.q, old_q);
+        sign_and_verify(&mock, &w.private_share, 1);
+
+        // P1 is unreachable: it may have switched, keep the pending share for the next load
+        let p2 = w.private_share.master_key.public.p2;
+        w.pending_rotation = Some(begin_rotation(&w.private_share, &mock).unwrap());
+        mock.set_unavailable(true);
+        let err = w.finish_rotation(&mock, wallet_file).unwrap_err();
+        assert_eq!(err.code(), 200);
+        assert!(w.pending_rotation.is_some());
+        assert_eq!(w.private_share.master_key.public.p2, p2);
+        mock.set_unavailable(false);
+
+        // P1 dropped the rotation: keep the current share
+        mock.drop_pending_rotation(&w.id);
+        let err = w.finish_rotation(&mock, wallet_file).unwrap_err();
+        assert_eq!(err.code(), 202);
+        assert!(w.pending_rotation.is_none());
+        assert_eq!(w.private_share.master_key.public.p2, p2);
+        sign_and_verify(&mock, &w.private_share, 1);
+
+        let _ = std::fs::remove_file(wallet_file);
+        let _ = std::fs::remove_file(format!("{}.1", wallet_file));
+    }
+
+    #[test]
+    fn test_send_eth_from_wallet_path_offline() {
+        let wallet_file = std::env::temp_dir().join("tss_send_path_w.json");
+        let wallet_file = wallet_file.to_str().unwrap();
+        let mock = MockParty1::new();
+        let mut w = Wallet::new(&mock, "testnet", "eth");
+        w.get_crypto_address();
+        let from = w.addresses_derivation_map.keys().next().unwrap().clone();
+        w.presign(&mock, 2).unwrap();
+        w.save_to(wallet_file);
+
+        let mut w = Wallet::load_from(wallet_file);
+        let txid = w.send(
+            &from,
+            "0x8e9a3e84d8ab53ebec5ca3f3ff9ae3f4c8c1a05f",
+            0.01,
+            &mock,
+            wallet_file,
+        );
+        assert!(!txid.is_empty());
+        assert_eq!(w.presignatures.len(), 1);
+        // The used presignature is gone from the file the wallet came from
+        assert_eq!(Wallet::load_from(wallet_file).presignatures.len(), 1);
+        assert_eq!(mock.presignature_count(&w.id), 1);
+
+        let _ = std::fs::remove_file(wallet_file);
+        for version in 1..=3 {
+            let _ = std::fs::remove_file(format!("{}.{}", wallet_file, version));
+        }
+    }
+
+    #[test]
+    fn test_encrypted_wallet_offline() {
+        let wallet_file = std::env::temp_dir().join("tss_encrypted_w.json");
+        let wallet_file = wallet_file.to_str().unwrap();
+        let mock = MockParty1::new();
+        let mut w = Wallet::new(&mock, "testnet", "eth");
+        w.set_passphrase(Some("correct horse")).unwrap();
+        w.save_to(wallet_file);
+
+        let data = std::fs::read_to_string(wallet_file).unwrap();
+        assert!(!data.contains(&w.id));
+        assert_eq!(
+            Wallet::load_from_with_passphrase(wallet_file, Some("wrong horse"))
+                .err()
+                .unwrap()
+                .code(),
+            207
+        );
+        assert!(Wallet::load_from_with_passphrase(wallet_file, None).is_err());
+
+        let mut loaded =
+            Wallet::load_from_with_passphrase(wallet_file, Some("correct horse")).unwrap();
+        assert!(loaded.is_encrypted());
+        assert_eq!(
+            loaded.private_share.master_key.public.q,
+            w.private_share.master_key.public.q
+        );
+
+        // Changing the passphrase, then dropping encryption
+        loaded
+            .change_passphrase(Some("battery staple"), wallet_file)
+            .unwrap();
+        assert!(Wallet::load_from_with_passphrase(wallet_file, Some("battery staple")).is_ok());
+        // No version is left readable with the old passphrase
+        assert!(std::fs::metadata(format!("{}.1", wallet_file)).is_err());
+        loaded.set_passphrase(None).unwrap();
+        loaded.save_to(wallet_file);
+        assert!(!Wallet::load_from_with_passphrase(wallet_file, None)
+            .unwrap()
+            .is_encrypted());
+
+        let _ = std::fs::remove_file(wallet_file);
+        let _ = std::fs::remove_file(format!("{}.1", wallet_file));
+    }
+
+    #[test]
+    fn test_recover_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        mock.set_last_derived_pos(&private_share.id, 12);
+
+        let escrow = Escrow::generate();
+        let backup = backup_client_mk(
+            &private_share,
+            &escrow.get_public_key(),
+            Some("btc"),
+            Some("testnet"),
+        )
+        .unwrap();
+        let backup = ClientBackup::from_json(&backup).unwrap();
+        assert_eq!(backup.version, CLIENT_BACKUP_VERSION);
+        assert_eq!(backup.network.as_deref(), Some("testnet"));
+        assert_eq!(
+            backup.escrow_key_fingerprint,
+            Some(escrow::fingerprint(&escrow.get_public_key()))
+        );
+
+        let g: GE = ECPoint::generator();
+        let sk = Msegmentation::decrypt(
+            &backup.encryptions,
+            &g,
+            &escrow.get_private_key(),
+            &backup.segment_size,
+        )
+        .unwrap();
+        let recovered = PrivateShare {
+            id: backup.id,
+            master_key: MasterKey2::recover_master_key(sk, backup.public, backup.chain_code),
+        };
+        let pos: u32 = mock
+            .post(&format!("ecdsa/{}/recover", recovered.id))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(pos, 12);
+        assert_eq!(
+            recovered.master_key.public.q,
+            private_share.master_key.public.q
+        );
+        sign_and_verify(&mock, &recovered, 1);
+    }
+
+    #[test]
+    fn test_legacy_backup_migration_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let escrow = Escrow::generate();
+        let y = escrow.get_public_key();
+        let g: GE = ECPoint::generator();
+
+        // Pre-versioning backups were a bare tuple
+        let (segments, encryptions) = private_share.master_key.private.to_encrypted_segment(
+            escrow::SEGMENT_SIZE,
+            escrow::NUM_SEGMENTS,
+            &y,
+            &g,
+        );
+        let proof = Proof::prove(&segments, &encryptions, &g, &y, &escrow::SEGMENT_SIZE);
+        let legacy: (Helgamalsegmented, Proof, Party2Public, BigInt, String) = (
+            encryptions,
+            proof,
+            private_share.master_key.public.clone(),
+            private_share.master_key.chain_code.clone(),
+            private_share.id.clone(),
+        );
+        let legacy = serde_json::to_string(&legacy).unwrap();
+
+        verify_client_backup(y, &legacy).unwrap();
+        let backup = ClientBackup::from_json(&legacy).unwrap();
+        assert_eq!(backup.id, private_share.id);
+        assert_eq!(backup.segment_size, escrow::SEGMENT_SIZE);
+        assert!(backup.coin_type.is_none() && backup.escrow_key_fingerprint.is_none());
+
+        let mut newer: serde_json::Value =
+            serde_json::from_str(&backup.to_json().unwrap()).unwrap();
+        newer["version"] = serde_json::json!(CLIENT_BACKUP_VERSION + 1);
+        let err = ClientBackup::from_json(&newer.to_string()).err().unwrap();
+        assert_eq!(err.code(), 206);
+    }
+
+    #[test]
+    fn test_threshold_escrow_recovery_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        for (t, n) in &[(0, 3), (4, 3)] {
+            let err = ThresholdEscrow::generate(*t, *n).err().unwrap();
+            assert!(matches!(err, TssError::InvalidArgument(_)));
+        }
+        let (threshold_escrow, shares) = ThresholdEscrow::generate(2, 3).unwrap();
+        for share in &shares {
+            share.verify().unwrap();
+        }
+
+        let backup = backup_client_mk(
+            &private_share,
+            &threshold_escrow.get_public_key(),
+            None,
+            None,
+        )
+        .unwrap();
+        verify_client_backup(threshold_escrow.get_public_key(), &backup).unwrap();
+        let backup = ClientBackup::from_json(&backup).unwrap();
+
+        let first = shares[0].partial_decrypt(&backup);
+        let err = threshold_escrow
+            .decrypt_backup(&backup, &[first])
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), 203);
+
+        // A partial decryption claimed for another custodian fails its proof
+        let mut forged = shares[0].partial_decrypt(&backup);
+        forged.index = 2;
+        let third = shares[2].partial_decrypt(&backup);
+        assert!(threshold_escrow
+            .decrypt_backup(&backup, &[forged, third])
+            .is_err());
+
+        // Invalid partials are skipped as long as enough valid ones remain
+        let mut forged = shares[0].partial_decrypt(&backup);
+        forged.index = 2;
+        let partials = vec![
+            forged,
+            shares[2].partial_decrypt(&backup),
+            shares[0].partial_decrypt(&backup),
+        ];
+        let recovery_key = escrow::RecoveryKey::Custodians {
+            escrow: threshold_escrow,
+            partials,
+        };
+        let sk = recovery_key.decrypt_backup(&backup).unwrap();
+        let g: GE = ECPoint::generator();
+        assert_eq!(g * sk, private_share.master_key.public.p2);
+    }
+
+    #[test]
+    fn test_exit_full_private_key_offline() {
+        let mock = MockParty1::new();
+        let private_share = get_private_share(&mock).unwrap();
+        let mk1 = mock.party_one_master_key(&private_share.id).unwrap();
+
+        // P1's share escrowed the way the server backs it up
+        let escrow = Escrow::generate();
+        let g: GE = ECPoint::generator();
+        let (_, p1_encryptions) = mk1.private.to_encrypted_segment(
+            escrow::SEGMENT_SIZE,
+            escrow::NUM_SEGMENTS,
+            &escrow.get_public_key(),
+            &g,
+        );
+
+        let (_, child_mk) = derive_new_key(&private_share, 4);
+        let full_key = recover_full_private_key(
+            &private_share,
+            &p1_encryptions,
+            &escrow.get_private_key(),
+            BigInt::from(0),
+            BigInt::from(5),
+        )
+        .unwrap();
+        assert_eq!(full_key.public, child_mk.public.q);
+        assert_eq!(full_key.eth_address(), pubkey_to_eth_address(&child_mk));
+        assert_eq!(
+            full_key.btc_address("testnet").unwrap(),
+            to_bitcoin_address("testnet", &child_mk).unwrap()
+        );
+        assert_eq!(full_key.to_hex().len(), 64);
+        assert!(!format!("{:?}", full_key).contains(full_key.to_hex().as_str()));
+
+        let wif = full_key.to_wif("testnet").unwrap();
+        let private_key = bitcoin::PrivateKey::from_wif(&wif).unwrap();
+        assert_eq!(hex::encode(&private_key.key[..]), *full_key.to_hex());
+
+        // Escrow secret of another backup
+        let err = recover_full_private_key(
+            &private_share,
+            &p1_encryptions,
+            &Escrow::generate().get_private_key(),
+            BigInt::from(0),
+            BigInt::from(5),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.code(), 203);
+    }
+
+    #[tokio::test]
+    async fn test_async_keygen_rotate_sign_offline() {
+        let mock = MockParty1::new();
+        let private_share = a_get_private_share(&mock).await.unwrap();
+        let old_q = private_share.master_key.public.q;
+
+        let rotated_private_share = a_rotate_private_share(private_share, &mock).await.unwrap();
+        assert_eq!(rotated_private_share.master_key.public.q, old_q);
+
+        let x_pos = BigInt::from(0);
+        let y_pos = BigInt::from(1);
+        let child_mk = rotated_private_share
+            .master_key
+            .get_child(vec![x_pos.clone(), y_pos.clone()]);
+        let message = BigInt::from_hex(MESSAGE_HEX).unwrap();
+        let sig = a_sign(
+            &mock,
+            message.clone(),
+            &child_mk,
+            x_pos,
+            y_pos,
+            &rotated_private_share.id,
+        )
+        .await
+        .unwrap();
+
+        let signature = party_one::Signature { r: sig.r, s: sig.s };
+        assert!(party_one::verify(&signature, &child_mk.public.q, &message).is_ok());
+    }
+}
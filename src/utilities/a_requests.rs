@@ -1,52 +1,114 @@
 use std::time::Instant;
 
-use anyhow::Result;
 use floating_duration::TimeFormat;
 
+use super::err_handling::RequestError;
+use super::policy::{default_request_policy, RequestPolicy};
+
 #[derive(Debug)]
 pub struct AsyncClientShim {
     pub client: reqwest::Client,
     pub auth_token: Option<String>,
     pub user_id: String,
     pub endpoint: String,
+    pub policy: RequestPolicy,
 }
 
 impl AsyncClientShim {
     pub fn new(endpoint: String, auth_token: Option<String>, user_id: String) -> AsyncClientShim {
-        let client = reqwest::Client::new();
+        AsyncClientShim::with_policy(endpoint, auth_token, user_id, default_request_policy())
+    }
+
+    pub fn with_policy(
+        endpoint: String,
+        auth_token: Option<String>,
+        user_id: String,
+        policy: RequestPolicy,
+    ) -> AsyncClientShim {
+        let client = reqwest::Client::builder()
+            .timeout(policy.timeout())
+            .build()
+            .unwrap();
 
         AsyncClientShim {
             client,
             auth_token,
             user_id,
             endpoint,
+            policy,
         }
     }
 }
 
-pub async fn a_post<V>(client_shim: &AsyncClientShim, path: &str) -> Result<Option<V>>
+pub async fn a_post<V>(client_shim: &AsyncClientShim, path: &str) -> Result<Option<V>, RequestError>
 where
     V: serde::de::DeserializeOwned,
 {
-    base_postb(client_shim, path, "{}").await
+    base_postb(client_shim, path, "{}", false).await
 }
 
-pub async fn a_postb<T, V>(client_shim: &AsyncClientShim, path: &str, body: T) -> Result<Option<V>>
+pub async fn a_postb<T, V>(
+    client_shim: &AsyncClientShim,
+    path: &str,
+    body: T,
+) -> Result<Option<V>, RequestError>
+where
+    T: serde::ser::Serialize,
+    V: serde::de::DeserializeOwned,
+{
+    base_postb(client_shim, path, body, false).await
+}
+
+pub async fn a_postb_idempotent<T, V>(
+    client_shim: &AsyncClientShim,
+    path: &str,
+    body: T,
+) -> Result<Option<V>, RequestError>
 where
     T: serde::ser::Serialize,
     V: serde::de::DeserializeOwned,
 {
-    base_postb(client_shim, path, body).await
+    base_postb(client_shim, path, body, true).await
 }
 
 pub async fn base_postb<T, V>(
     client_shim: &AsyncClientShim,
     path: &str,
     body: T,
-) -> Result<Option<V>>
+    idempotent: bool,
+) -> Result<Option<V>, RequestError>
 where
     T: serde::ser::Serialize,
     V: serde::de::DeserializeOwned,
+{
+    // Serialize once up front: the body is resent as-is on retry, and no borrow of `T`
+    // has to live across an await point.
+    let body = serde_json::to_value(body).map_err(|e| RequestError::Encode {
+        path: path.to_owned(),
+        e: e.to_string(),
+    })?;
+    let mut attempt = 0;
+    loop {
+        match send_once(client_shim, path, &body).await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < client_shim.policy.max_retries && e.is_retryable(idempotent) => {
+                let delay = client_shim.policy.backoff(attempt);
+                warn!("(req {}, attempt {}) {}, retrying", path, attempt + 1, e);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn send_once<V>(
+    client_shim: &AsyncClientShim,
+    path: &str,
+    body: &serde_json::Value,
+) -> Result<Option<V>, RequestError>
+where
+    V: serde::de::DeserializeOwned,
 {
     let start = Instant::now();
 
@@ -58,7 +120,31 @@ where
         b = b.bearer_auth(client_shim.auth_token.clone().unwrap());
         b = b.header("user_id", client_shim.user_id.clone());
     }
-    let res = b.json(&body).send().await?;
+    let res = b
+        .json(body)
+        .send()
+        .await
+        .map_err(|e| RequestError::from_reqwest(path, e))?;
     info!("(req {}, took: {})", path, TimeFormat(start.elapsed()));
-    Ok(Some(res.json::<V>().await?))
+
+    let status = res.status();
+    let value = res
+        .text()
+        .await
+        .map_err(|e| RequestError::from_reqwest(path, e))?;
+
+    if !status.is_success() {
+        return Err(RequestError::from_status(path, status.as_u16(), &value));
+    }
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match serde_json::from_str(value.as_str()) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(RequestError::Protocol {
+            path: path.to_owned(),
+            e: e.to_string(),
+        }),
+    }
 }
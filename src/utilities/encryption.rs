@@ -0,0 +1,229 @@
+// Passphrase encryption of files holding key material: scrypt derives the key,
+// ChaCha20-Poly1305 encrypts. The file is a JSON document whose header (version and KDF
+// parameters) is authenticated together with the ciphertext.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use zeroize::Zeroizing;
+
+use crate::utilities::err_handling::TssError;
+
+pub const ENCRYPTED_FILE_VERSION: u32 = 1;
+const CIPHER: &str = "chacha20poly1305";
+const KDF: &str = "scrypt";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// Highest scrypt cost a file may ask for; the header is only authenticated after the KDF ran
+const MAX_LOG_N: u8 = 20;
+
+/// scrypt cost parameters, stored in the header so they can be raised for new files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            log_n: 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    // Reject costs above what this crate writes before spending them on a header that may
+    // have been tampered with
+    fn check(&self) -> Result<(), TssError> {
+        let default = KdfParams::default();
+        if self.log_n > MAX_LOG_N || self.r != default.r || self.p != default.p {
+            return Err(TssError::Serialization(format!(
+                "unsupported scrypt parameters: log_n {}, r {}, p {}",
+                self.log_n, self.r, self.p
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    kdf: String,
+    kdf_params: KdfParams,
+    cipher: String,
+    salt: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EncryptedFile {
+    #[serde(flatten)]
+    header: Header,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Key derived from a passphrase. Kept by the owner of a file so it can be saved again
+/// without asking for the passphrase.
+pub struct FileKey {
+    key: Zeroizing<[u8; 32]>,
+    salt: [u8; SALT_LEN],
+    kdf_params: KdfParams,
+}
+
+impl FileKey {
+    /// Derive a key for a new file, with a fresh salt.
+    pub fn new(passphrase: &str) -> Result<FileKey, TssError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        FileKey::derive(passphrase, salt, KdfParams::default())
+    }
+
+    fn derive(
+        passphrase: &str,
+        salt: [u8; SALT_LEN],
+        kdf_params: KdfParams,
+    ) -> Result<FileKey, TssError> {
+        let params = scrypt::Params::new(kdf_params.log_n, kdf_params.r, kdf_params.p)
+            .map_err(|e| TssError::Storage(format!("invalid scrypt parameters: {}", e)))?;
+        let mut key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(passphrase.as_bytes(), &salt, &params, &mut key[..])
+            .map_err(|e| TssError::Storage(format!("key derivation failed: {}", e)))?;
+
+        Ok(FileKey {
+            key,
+            salt,
+            kdf_params,
+        })
+    }
+
+    fn header(&self) -> Header {
+        Header {
+            version: ENCRYPTED_FILE_VERSION,
+            kdf: KDF.to_owned(),
+            kdf_params: self.kdf_params,
+            cipher: CIPHER.to_owned(),
+            salt: hex::encode(self.salt),
+        }
+    }
+
+    /// Encrypt `plaintext` into the JSON file format.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String, TssError> {
+        let header = self.header();
+        let aad = serde_json::to_vec(&header)?;
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key[..]));
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| TssError::Storage("encryption failed".to_owned()))?;
+
+        Ok(serde_json::to_string(&EncryptedFile {
+            header,
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })?)
+    }
+}
+
+/// Whether `data` is in the encrypted file format (as opposed to legacy plaintext JSON).
+pub fn is_encrypted(data: &str) -> bool {
+    serde_json::from_str::<EncryptedFile>(data).is_ok()
+}
+
+/// Decrypt a file written by `FileKey::encrypt`. Also returns the key, to save the file
+/// again. A wrong passphrase and a tampered file both fail authentication.
+pub fn decrypt(data: &str, passphrase: &str) -> Result<(FileKey, Zeroizing<Vec<u8>>), TssError> {
+    let file: EncryptedFile = serde_json::from_str(data)?;
+    let header = &file.header;
+    if header.version != ENCRYPTED_FILE_VERSION || header.kdf != KDF || header.cipher != CIPHER {
+        return Err(TssError::Storage(format!(
+            "unsupported encrypted file: version {}, {}, {}",
+            header.version, header.kdf, header.cipher
+        )));
+    }
+
+    let salt: [u8; SALT_LEN] = decode_hex(&header.salt, "salt")?;
+    let nonce: [u8; NONCE_LEN] = decode_hex(&file.nonce, "nonce")?;
+    let ciphertext = hex::decode(&file.ciphertext)
+        .map_err(|e| TssError::Storage(format!("invalid ciphertext: {}", e)))?;
+
+    header.kdf_params.check()?;
+    let key = FileKey::derive(passphrase, salt, header.kdf_params)?;
+    let aad = serde_json::to_vec(header)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&key.key[..]));
+    let plaintext = cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| {
+            TssError::Storage("decryption failed: wrong passphrase or corrupted file".to_owned())
+        })?;
+
+    Ok((key, Zeroizing::new(plaintext)))
+}
+
+fn decode_hex<const N: usize>(s: &str, field: &str) -> Result<[u8; N], TssError> {
+    let bytes =
+        hex::decode(s).map_err(|e| TssError::Storage(format!("invalid {}: {}", field, e)))?;
+    bytes
+        .try_into()
+        .map_err(|_| TssError::Storage(format!("invalid {} length", field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, is_encrypted, FileKey};
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = FileKey::new("correct horse").unwrap();
+        let data = key.encrypt(b"{\"secret\":1}").unwrap();
+        assert!(is_encrypted(&data));
+        assert!(!is_encrypted("{\"secret\":1}"));
+
+        let (_, plaintext) = decrypt(&data, "correct horse").unwrap();
+        assert_eq!(&plaintext[..], b"{\"secret\":1}");
+
+        let err = decrypt(&data, "wrong horse").err().unwrap();
+        assert_eq!(err.code(), 207);
+
+        // The header is authenticated too
+        let tampered = data.replace("\"log_n\":15", "\"log_n\":14");
+        assert!(decrypt(&tampered, "correct horse").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_rejects_costly_kdf_params() {
+        let data = FileKey::new("correct horse")
+            .unwrap()
+            .encrypt(b"{\"secret\":1}")
+            .unwrap();
+        for (from, to) in &[
+            ("\"log_n\":15", "\"log_n\":40"),
+            ("\"r\":8", "\"r\":4096"),
+            ("\"p\":1", "\"p\":64"),
+        ] {
+            let tampered = data.replace(from, to);
+            assert_ne!(tampered, data);
+            let err = decrypt(&tampered, "correct horse").err().unwrap();
+            assert_eq!(err.code(), 206);
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use std::ffi::CString;
+use std::mem;
 use std::os::raw::c_char;
 
 use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Error, Debug)]
 pub enum ErrorFFIKind {
@@ -15,8 +17,427 @@ pub enum ErrorFFIKind {
     E103 { msg: String, e: String },
     #[error("E104: From JSON to struct parsing error: {msg}\n {e}")]
     E104 { msg: String, e: String },
+    #[error("E105: Null pointer passed for: {msg}")]
+    E105 { msg: String },
+    #[error("E106: Panic caught at FFI boundary: {msg}\n {e}")]
+    E106 { msg: String, e: String },
+    #[error("E{}: {msg}\n {e}", .e.code())]
+    Tss { msg: String, e: TssError },
+}
+
+impl ErrorFFIKind {
+    pub fn code(&self) -> u32 {
+        match self {
+            ErrorFFIKind::E100 { .. } => 100,
+            ErrorFFIKind::E101 { .. } => 101,
+            ErrorFFIKind::E102 { .. } => 102,
+            ErrorFFIKind::E103 { .. } => 103,
+            ErrorFFIKind::E104 { .. } => 104,
+            ErrorFFIKind::E105 { .. } => 105,
+            ErrorFFIKind::E106 { .. } => 106,
+            ErrorFFIKind::Tss { e, .. } => e.code(),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ErrorFFIKind::E100 { .. } => "c_string_decode",
+            ErrorFFIKind::E101 { .. } => "c_string_encode",
+            ErrorFFIKind::E102 { .. } => "json_encode",
+            ErrorFFIKind::E103 { .. } => "tss_communication",
+            ErrorFFIKind::E104 { .. } => "json_decode",
+            ErrorFFIKind::E105 { .. } => "null_pointer",
+            ErrorFFIKind::E106 { .. } => "panic",
+            ErrorFFIKind::Tss { e, .. } => e.kind(),
+        }
+    }
+}
+
+/// Crate-wide error of the protocol, wallet and transaction APIs.
+///
+/// Every variant has a stable numeric code (see `TssError::code`) which is what the FFI
+/// layer reports, so host apps can match on it instead of on the message text.
+#[derive(Error, Debug)]
+pub enum TssError {
+    #[error("transport error: {0}")]
+    Transport(RequestError),
+    #[error("authentication failed: {0}")]
+    Auth(RequestError),
+    #[error("protocol error in {step}: {msg}")]
+    Protocol { step: String, msg: String },
+    #[error("verification failed in {step}: {msg}")]
+    CryptoVerification { step: String, msg: String },
+    #[error("insufficient funds: need {needed}, available {available}")]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("invalid address {address}: {msg}")]
+    InvalidAddress { address: String, msg: String },
+    #[error("serialization error: {0}")]
+    Serialization(String),
+    #[error("storage error: {0}")]
+    Storage(String),
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+impl TssError {
+    pub fn code(&self) -> u32 {
+        match self {
+            TssError::Transport(_) => 200,
+            TssError::Auth(_) => 201,
+            TssError::Protocol { .. } => 202,
+            TssError::CryptoVerification { .. } => 203,
+            TssError::InsufficientFunds { .. } => 204,
+            TssError::InvalidAddress { .. } => 205,
+            TssError::Serialization(_) => 206,
+            TssError::Storage(_) => 207,
+            TssError::InvalidArgument(_) => 208,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TssError::Transport(_) => "transport",
+            TssError::Auth(_) => "auth",
+            TssError::Protocol { .. } => "protocol",
+            TssError::CryptoVerification { .. } => "crypto_verification",
+            TssError::InsufficientFunds { .. } => "insufficient_funds",
+            TssError::InvalidAddress { .. } => "invalid_address",
+            TssError::Serialization(_) => "serialization",
+            TssError::Storage(_) => "storage",
+            TssError::InvalidArgument(_) => "invalid_argument",
+        }
+    }
+
+    pub fn protocol(step: &str, msg: impl std::fmt::Display) -> TssError {
+        TssError::Protocol {
+            step: step.to_owned(),
+            msg: msg.to_string(),
+        }
+    }
+
+    pub fn verification(step: &str, msg: impl std::fmt::Display) -> TssError {
+        TssError::CryptoVerification {
+            step: step.to_owned(),
+            msg: msg.to_string(),
+        }
+    }
+
+    pub fn invalid_address(address: &str, msg: impl std::fmt::Display) -> TssError {
+        TssError::InvalidAddress {
+            address: address.to_owned(),
+            msg: msg.to_string(),
+        }
+    }
+}
+
+impl From<RequestError> for TssError {
+    fn from(e: RequestError) -> Self {
+        if e.is_auth() {
+            TssError::Auth(e)
+        } else if e.is_protocol_abort() {
+            let step = match &e {
+                RequestError::Http { path, .. } => path.clone(),
+                _ => String::new(),
+            };
+            TssError::Protocol {
+                step,
+                msg: e.to_string(),
+            }
+        } else {
+            TssError::Transport(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for TssError {
+    fn from(e: serde_json::Error) -> Self {
+        TssError::Serialization(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for TssError {
+    fn from(e: std::io::Error) -> Self {
+        TssError::Storage(e.to_string())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("connection to {path} failed: {e}")]
+    Connect { path: String, e: String },
+    #[error("request to {path} timed out: {e}")]
+    Timeout { path: String, e: String },
+    #[error("request to {path} failed: {e}")]
+    Network { path: String, e: String },
+    #[error("cannot encode request to {path}: {e}")]
+    Encode { path: String, e: String },
+    #[error("invalid response from {path}: {e}")]
+    Protocol { path: String, e: String },
+    #[error("server returned HTTP {status} for {path}: {message}")]
+    Http {
+        status: u16,
+        message: String,
+        path: String,
+    },
+}
+
+impl RequestError {
+    pub fn from_reqwest(path: &str, e: reqwest::Error) -> RequestError {
+        let path = path.to_owned();
+        let e_str = e.to_string();
+        if e.is_connect() {
+            RequestError::Connect { path, e: e_str }
+        } else if e.is_timeout() {
+            RequestError::Timeout { path, e: e_str }
+        } else if e.is_decode() {
+            RequestError::Protocol { path, e: e_str }
+        } else {
+            RequestError::Network { path, e: e_str }
+        }
+    }
+
+    /// Build an `Http` error from a non-2xx response, keeping the server's own message when
+    /// the body is one of its JSON error objects.
+    pub fn from_status(path: &str, status: u16, body: &str) -> RequestError {
+        RequestError::Http {
+            status,
+            message: server_error_message(body),
+            path: path.to_owned(),
+        }
+    }
+
+    /// A request that never reached the server is always safe to resend; anything else is
+    /// only resent when the step is idempotent.
+    pub fn is_retryable(&self, idempotent: bool) -> bool {
+        match self {
+            RequestError::Connect { .. } => true,
+            RequestError::Timeout { .. } | RequestError::Network { .. } => idempotent,
+            RequestError::Http { status, .. } => {
+                idempotent && matches!(status, 429 | 502 | 503 | 504)
+            }
+            RequestError::Encode { .. } | RequestError::Protocol { .. } => false,
+        }
+    }
+
+    /// Token missing, expired or not allowed for this user.
+    pub fn is_auth(&self) -> bool {
+        matches!(
+            self,
+            RequestError::Http {
+                status: 401 | 403,
+                ..
+            }
+        )
+    }
+
+    /// The server refused a protocol round (unknown session, bad proof, wrong order).
+    pub fn is_protocol_abort(&self) -> bool {
+        match self {
+            RequestError::Http { status, .. } => {
+                (400..500).contains(status) && !matches!(status, 401 | 403 | 429)
+            }
+            _ => false,
+        }
+    }
+
+    /// The server does not offer the endpoint (older deployments without batch rounds).
+    pub fn is_unsupported(&self) -> bool {
+        matches!(
+            self,
+            RequestError::Http {
+                status: 404 | 405 | 501,
+                ..
+            }
+        )
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        matches!(self, RequestError::Http { status, .. } if *status >= 500)
+    }
+}
+
+const MAX_ERROR_BODY_LEN: usize = 512;
+
+fn server_error_message(body: &str) -> String {
+    if let Ok(serde_json::Value::Object(obj)) = serde_json::from_str(body) {
+        for key in ["error", "message", "msg", "Msg"] {
+            match obj.get(key) {
+                Some(serde_json::Value::String(m)) => return m.clone(),
+                Some(serde_json::Value::Null) | None => continue,
+                Some(v) => return v.to_string(),
+            }
+        }
+    }
+
+    let body = body.trim();
+    if body.is_empty() {
+        return "<empty body>".to_owned();
+    }
+    match body.char_indices().nth(MAX_ERROR_BODY_LEN) {
+        Some((idx, _)) => format!("{}...", &body[..idx]),
+        None => body.to_owned(),
+    }
+}
+
+/// Result of every exported FFI function: exactly one of `ok` and `error` is set, except
+/// where a function documents a value that survives the error (`ok_and_error_to_c_string`).
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FFIResponse<T> {
+    pub ok: Option<T>,
+    pub error: Option<FFIError>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FFIError {
+    /// Stable code, `"E100"`..`"E106"` or one of the `"E2xx"` codes of `TssError`.
+    pub code: String,
+    pub kind: String,
+    pub message: String,
+}
+
+impl From<&ErrorFFIKind> for FFIError {
+    fn from(e: &ErrorFFIKind) -> Self {
+        FFIError {
+            code: format!("E{}", e.code()),
+            kind: e.kind().to_owned(),
+            message: e.to_string(),
+        }
+    }
 }
 
 pub fn error_to_c_string(e: ErrorFFIKind) -> *mut c_char {
-    CString::new(format!("{}", e)).unwrap().into_raw()
+    let response: FFIResponse<()> = FFIResponse {
+        ok: None,
+        error: Some(FFIError::from(&e)),
+    };
+    // serde_json escapes control characters, so the output never holds an interior nul
+    let response_json = serde_json::to_string(&response).unwrap_or_default();
+    CString::new(response_json).unwrap_or_default().into_raw()
+}
+
+/// Wrap `value` in a success envelope. `msg` names the value in the error reported if it
+/// cannot be encoded.
+pub fn ok_to_c_string<T: serde::Serialize>(value: &T, msg: &str) -> *mut c_char {
+    response_to_c_string(
+        &FFIResponse {
+            ok: Some(value),
+            error: None,
+        },
+        msg,
+    )
+}
+
+/// Report `e` together with `value`, which the caller must keep despite the error (e.g.
+/// a rotated share P1 may already use).
+pub fn ok_and_error_to_c_string<T: serde::Serialize>(
+    value: &T,
+    e: ErrorFFIKind,
+    msg: &str,
+) -> *mut c_char {
+    response_to_c_string(
+        &FFIResponse {
+            ok: Some(value),
+            error: Some(FFIError::from(&e)),
+        },
+        msg,
+    )
+}
+
+fn response_to_c_string<T: serde::Serialize>(response: &FFIResponse<&T>, msg: &str) -> *mut c_char {
+    // The value may be a private share: keep it in one buffer that ends up in the CString
+    // (wiped by `cstring_free`) and wipe it when building the CString fails
+    let mut response_json = match serde_json::to_vec(response) {
+        Ok(s) => Zeroizing::new(s),
+        Err(e) => {
+            return error_to_c_string(ErrorFFIKind::E102 {
+                msg: msg.to_owned(),
+                e: e.to_string(),
+            })
+        }
+    };
+    response_json.reserve_exact(1);
+
+    match CString::new(mem::take(&mut *response_json)) {
+        Ok(s) => s.into_raw(),
+        Err(e) => {
+            let err = e.to_string();
+            e.into_vec().zeroize();
+            error_to_c_string(ErrorFFIKind::E101 {
+                msg: msg.to_owned(),
+                e: err,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        error_to_c_string, ok_and_error_to_c_string, ok_to_c_string, ErrorFFIKind, FFIError,
+        FFIResponse, RequestError, TssError,
+    };
+    use std::ffi::CString;
+
+    fn take_response<T: serde::de::DeserializeOwned>(
+        ptr: *mut std::os::raw::c_char,
+    ) -> FFIResponse<T> {
+        let s = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        serde_json::from_str(&s).unwrap()
+    }
+
+    #[test]
+    fn test_ffi_envelope() {
+        let ok: FFIResponse<u32> = take_response(ok_to_c_string(&7u32, "value"));
+        assert_eq!(ok.ok, Some(7));
+        assert!(ok.error.is_none());
+
+        let err: FFIResponse<u32> = take_response(error_to_c_string(ErrorFFIKind::Tss {
+            msg: "raw_tx".to_owned(),
+            e: TssError::InsufficientFunds {
+                needed: 10,
+                available: 3,
+            },
+        }));
+        assert!(err.ok.is_none());
+        let FFIError { code, kind, .. } = err.error.unwrap();
+        assert_eq!(code, "E204");
+        assert_eq!(kind, "insufficient_funds");
+
+        let both: FFIResponse<u32> = take_response(ok_and_error_to_c_string(
+            &7u32,
+            ErrorFFIKind::Tss {
+                msg: "rotated_private_share".to_owned(),
+                e: TssError::Storage("disk full".to_owned()),
+            },
+            "rotated_private_share",
+        ));
+        assert_eq!(both.ok, Some(7));
+        assert_eq!(both.error.unwrap().code, "E207");
+    }
+
+    #[test]
+    fn test_http_error_classification() {
+        let expired =
+            RequestError::from_status("ecdsa/keygen/first", 401, r#"{"Msg":"token expired"}"#);
+        assert!(expired.is_auth());
+        assert!(!expired.is_retryable(true));
+        assert_eq!(
+            expired.to_string(),
+            "server returned HTTP 401 for ecdsa/keygen/first: token expired"
+        );
+
+        let abort =
+            RequestError::from_status("ecdsa/sign/1/second", 400, r#"{"error":"bad proof"}"#);
+        assert!(abort.is_protocol_abort());
+        assert!(!abort.is_server_error());
+        assert!(!abort.is_unsupported());
+
+        let missing = RequestError::from_status("ecdsa/sign/1/batch/first", 404, "");
+        assert!(missing.is_unsupported());
+
+        let unavailable = RequestError::from_status("ecdsa/1/recover", 503, "");
+        assert!(unavailable.is_server_error());
+        assert!(unavailable.is_retryable(true));
+        assert!(!unavailable.is_retryable(false));
+    }
 }
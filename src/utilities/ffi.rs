@@ -1,18 +1,52 @@
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub mod ffi_utils {
-    use anyhow::Result;
     use std::{
         collections::HashMap,
         ffi::{CStr, CString},
         os::raw::c_char,
+        panic::{self, AssertUnwindSafe},
     };
+    use zeroize::{Zeroize, Zeroizing};
 
     use crate::{
         dto::ecdsa::{MKPosDto, PrivateShare},
-        utilities::{err_handling::ErrorFFIKind, requests::ClientShim},
+        utilities::{
+            err_handling::{error_to_c_string, ErrorFFIKind},
+            requests::ClientShim,
+        },
     };
 
+    /// Run the body of an exported function, turning a panic into an E106 error result.
+    /// Unwinding into the host app would abort it.
+    pub fn ffi_guard<F>(fn_name: &str, f: F) -> *mut c_char
+    where
+        F: FnOnce() -> *mut c_char,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(f)) {
+            Ok(s) => s,
+            Err(payload) => {
+                let e = if let Some(s) = payload.downcast_ref::<&str>() {
+                    s.to_string()
+                } else if let Some(s) = payload.downcast_ref::<String>() {
+                    s.clone()
+                } else {
+                    "unknown panic payload".to_owned()
+                };
+                error_to_c_string(ErrorFFIKind::E106 {
+                    msg: fn_name.to_owned(),
+                    e,
+                })
+            }
+        }
+    }
+
     pub fn get_str_from_c_char(c: *const c_char, err_msg: &str) -> Result<String, ErrorFFIKind> {
+        if c.is_null() {
+            return Err(ErrorFFIKind::E105 {
+                msg: err_msg.to_owned(),
+            });
+        }
+
         let raw = unsafe { CStr::from_ptr(c) };
         let s = match raw.to_str() {
             Ok(s) => s,
@@ -42,7 +76,10 @@ pub mod ffi_utils {
     pub fn get_private_share_from_raw(
         c_private_share_json: *const c_char,
     ) -> Result<PrivateShare, ErrorFFIKind> {
-        let private_share_json = get_str_from_c_char(c_private_share_json, "private_share_json")?;
+        let private_share_json = Zeroizing::new(get_str_from_c_char(
+            c_private_share_json,
+            "private_share_json",
+        )?);
 
         let private_share: PrivateShare = match serde_json::from_str(&private_share_json) {
             Ok(s) => s,
@@ -60,8 +97,10 @@ pub mod ffi_utils {
     pub fn get_addresses_derivation_map_from_raw(
         c_addresses_derivation_map: *const c_char,
     ) -> Result<HashMap<String, MKPosDto>, ErrorFFIKind> {
-        let addresses_derivation_map_json =
-            get_str_from_c_char(c_addresses_derivation_map, "addresses_derivation_map_json")?;
+        let addresses_derivation_map_json = Zeroizing::new(get_str_from_c_char(
+            c_addresses_derivation_map,
+            "addresses_derivation_map_json",
+        )?);
 
         let addresses_derivation_map: HashMap<String, MKPosDto> =
             match serde_json::from_str(&addresses_derivation_map_json) {
@@ -82,6 +121,32 @@ pub mod ffi_utils {
         if cstring.is_null() {
             return;
         }
-        unsafe { CString::from_raw(cstring) };
+        // Returned strings may hold a private share or key: wipe before freeing
+        let cstring = unsafe { CString::from_raw(cstring) };
+        cstring.into_bytes_with_nul().zeroize();
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{ffi_guard, get_str_from_c_char};
+        use crate::utilities::err_handling::FFIResponse;
+        use std::ffi::CString;
+
+        #[test]
+        fn test_ffi_guard_and_null_pointer() {
+            assert_eq!(
+                get_str_from_c_char(std::ptr::null(), "endpoint")
+                    .unwrap_err()
+                    .code(),
+                105
+            );
+
+            let ptr = ffi_guard("boom", || panic!("unexpected state"));
+            let s = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+            let resp: FFIResponse<()> = serde_json::from_str(&s).unwrap();
+            let error = resp.error.unwrap();
+            assert_eq!(error.code, "E106");
+            assert!(error.message.contains("unexpected state"));
+        }
     }
 }
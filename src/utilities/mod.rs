@@ -1,7 +1,13 @@
 pub mod a_requests;
+pub mod encryption;
 pub mod err_handling;
 pub mod ffi;
+pub mod policy;
 pub mod requests;
+pub mod secret;
+pub mod session;
+pub mod storage;
+pub mod transport;
 
 use crate::dto::ecdsa::PrivateShare;
 use curv::BigInt;
@@ -0,0 +1,107 @@
+use std::os::raw::c_char;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind};
+use crate::utilities::ffi::ffi_utils::{ffi_guard, get_str_from_c_char};
+
+/// Timeout and retry settings applied to every request sent to Party 1.
+///
+/// Failures where the request never reached the server (connection refused, DNS) are
+/// retried for every step. Timeouts and other network failures are only retried for
+/// idempotent steps, since a protocol round may already have been applied by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RequestPolicy {
+    pub timeout_ms: u64,
+    pub max_retries: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl RequestPolicy {
+    pub const DEFAULT: RequestPolicy = RequestPolicy {
+        timeout_ms: 30_000,
+        max_retries: 3,
+        initial_backoff_ms: 500,
+        max_backoff_ms: 8_000,
+    };
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Delay before retry number `attempt` (starting at 0), doubling up to `max_backoff_ms`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt);
+        let delay = self.initial_backoff_ms.saturating_mul(factor);
+        Duration::from_millis(delay.min(self.max_backoff_ms))
+    }
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        RequestPolicy::DEFAULT
+    }
+}
+
+// Used by `ClientShim::new`, so the FFI entry points pick up whatever the host configured.
+static DEFAULT_REQUEST_POLICY: RwLock<RequestPolicy> = RwLock::new(RequestPolicy::DEFAULT);
+
+pub fn default_request_policy() -> RequestPolicy {
+    match DEFAULT_REQUEST_POLICY.read() {
+        Ok(p) => *p,
+        Err(_) => RequestPolicy::DEFAULT,
+    }
+}
+
+pub fn set_default_request_policy(policy: RequestPolicy) {
+    if let Ok(mut p) = DEFAULT_REQUEST_POLICY.write() {
+        *p = policy;
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn set_request_policy(c_policy_json: *const c_char) -> *mut c_char {
+    ffi_guard("set_request_policy", || {
+        let policy_json = match get_str_from_c_char(c_policy_json, "policy_json") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let policy: RequestPolicy = match serde_json::from_str(&policy_json) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "request_policy".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
+
+        set_default_request_policy(policy);
+
+        ok_to_c_string(&policy, "request_policy")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestPolicy;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_is_exponential_and_capped() {
+        let policy = RequestPolicy {
+            timeout_ms: 1_000,
+            max_retries: 10,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1_000,
+        };
+
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(800));
+        assert_eq!(policy.backoff(4), Duration::from_millis(1_000));
+        assert_eq!(policy.backoff(64), Duration::from_millis(1_000));
+    }
+}
@@ -1,45 +1,105 @@
-use anyhow::{anyhow, Result};
 use floating_duration::TimeFormat;
 use serde;
+use std::thread;
 use std::time::Instant;
 
+use super::err_handling::RequestError;
+use super::policy::{default_request_policy, RequestPolicy};
+
 #[derive(Debug)]
 pub struct ClientShim {
     pub client: reqwest::blocking::Client,
     pub auth_token: Option<String>,
     pub user_id: String,
     pub endpoint: String,
+    pub policy: RequestPolicy,
 }
 
 impl ClientShim {
     pub fn new(endpoint: String, auth_token: Option<String>, user_id: String) -> ClientShim {
-        let client = reqwest::blocking::Client::builder().build().unwrap();
+        ClientShim::with_policy(endpoint, auth_token, user_id, default_request_policy())
+    }
+
+    pub fn with_policy(
+        endpoint: String,
+        auth_token: Option<String>,
+        user_id: String,
+        policy: RequestPolicy,
+    ) -> ClientShim {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(policy.timeout())
+            .build()
+            .unwrap();
 
         ClientShim {
             client,
             auth_token,
             user_id,
             endpoint,
+            policy,
         }
     }
 }
 
-pub fn post<V>(client_shim: &ClientShim, path: &str) -> Result<Option<V>>
+pub fn post<V>(client_shim: &ClientShim, path: &str) -> Result<Option<V>, RequestError>
+where
+    V: serde::de::DeserializeOwned,
+{
+    _postb(client_shim, path, "{}", false)
+}
+
+pub fn postb<T, V>(client_shim: &ClientShim, path: &str, body: T) -> Result<Option<V>, RequestError>
+where
+    T: serde::ser::Serialize,
+    V: serde::de::DeserializeOwned,
+{
+    _postb(client_shim, path, body, false)
+}
+
+/// Same as `postb`, for steps the server can safely apply twice (reads, lookups), so
+/// timeouts and network failures are retried as well.
+pub fn postb_idempotent<T, V>(
+    client_shim: &ClientShim,
+    path: &str,
+    body: T,
+) -> Result<Option<V>, RequestError>
 where
+    T: serde::ser::Serialize,
     V: serde::de::DeserializeOwned,
 {
-    _postb(client_shim, path, "{}")
+    _postb(client_shim, path, body, true)
 }
 
-pub fn postb<T, V>(client_shim: &ClientShim, path: &str, body: T) -> Result<Option<V>>
+fn _postb<T, V>(
+    client_shim: &ClientShim,
+    path: &str,
+    body: T,
+    idempotent: bool,
+) -> Result<Option<V>, RequestError>
 where
     T: serde::ser::Serialize,
     V: serde::de::DeserializeOwned,
 {
-    _postb(client_shim, path, body)
+    let mut attempt = 0;
+    loop {
+        match send_once(client_shim, path, &body) {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < client_shim.policy.max_retries && e.is_retryable(idempotent) => {
+                let delay = client_shim.policy.backoff(attempt);
+                warn!("(req {}, attempt {}) {}, retrying", path, attempt + 1, e);
+                thread::sleep(delay);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
 }
 
-fn _postb<T, V>(client_shim: &ClientShim, path: &str, body: T) -> Result<Option<V>>
+fn send_once<T, V>(
+    client_shim: &ClientShim,
+    path: &str,
+    body: &T,
+) -> Result<Option<V>, RequestError>
 where
     T: serde::ser::Serialize,
     V: serde::de::DeserializeOwned,
@@ -54,14 +114,32 @@ where
         b = b.bearer_auth(client_shim.auth_token.clone().unwrap());
         b = b.header("user_id", client_shim.user_id.clone());
     }
-    let res = b.json(&body).send();
+    let res = b.json(body).send();
 
     info!("(req {}, took: {})", path, TimeFormat(start.elapsed()));
 
-    let value = match res {
-        Ok(v) => v.text()?,
-        Err(e) => return Err(anyhow!("HTTP POST with auth token failed: {}", e)),
+    let res = match res {
+        Ok(v) => v,
+        Err(e) => return Err(RequestError::from_reqwest(path, e)),
     };
 
-    Ok(Some(serde_json::from_str(value.as_str())?))
+    let status = res.status();
+    let value = res
+        .text()
+        .map_err(|e| RequestError::from_reqwest(path, e))?;
+
+    if !status.is_success() {
+        return Err(RequestError::from_status(path, status.as_u16(), &value));
+    }
+    if value.trim().is_empty() {
+        return Ok(None);
+    }
+
+    match serde_json::from_str(value.as_str()) {
+        Ok(v) => Ok(Some(v)),
+        Err(e) => Err(RequestError::Protocol {
+            path: path.to_owned(),
+            e: e.to_string(),
+        }),
+    }
 }
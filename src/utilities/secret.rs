@@ -0,0 +1,50 @@
+// Wiping of secret key material. The kms / curv types holding P2's share do not clear
+// themselves when dropped, so the types of this crate that own them do it here.
+
+use std::mem;
+
+use curv::elliptic::curves::secp256_k1::FE;
+use kms::ecdsa::two_party::MasterKey2;
+use multi_party_ecdsa::protocols::two_party_ecdsa::lindell_2017::party_two::Party2Private;
+use zeroize::Zeroize;
+
+pub(crate) const REDACTED: &str = "[REDACTED]";
+
+// `Party2Private` wraps the single scalar `x2`, which is private to multi-party-ecdsa
+const _: () = assert!(
+    mem::size_of::<Party2Private>() == mem::size_of::<FE>()
+        && mem::align_of::<Party2Private>() == mem::align_of::<FE>()
+);
+
+/// Zeroize the private share `x2` of `mk` in place. `mk` must not be used for signing
+/// afterwards.
+pub(crate) fn wipe_master_key(mk: &mut MasterKey2) {
+    // The scalar's own `Zeroize` clears whatever it holds before anything else is written
+    let x2 = unsafe { &mut *(&mut mk.private as *mut Party2Private as *mut FE) };
+    x2.zeroize();
+}
+
+#[cfg(test)]
+mod tests {
+    use curv::arithmetic::Converter;
+    use curv::BigInt;
+
+    use super::wipe_master_key;
+    use crate::dto::ecdsa::PrivateShare;
+    use crate::tests::common::{get_test_private_share, PRIVATE_SHARE_FILENAME};
+
+    #[test]
+    fn test_wipe_master_key() {
+        let private_share: PrivateShare = get_test_private_share(PRIVATE_SHARE_FILENAME);
+        let mut mk = private_share.master_key.get_child(vec![BigInt::from(0)]);
+        let x2 = serde_json::to_value(&mk.private).unwrap();
+
+        wipe_master_key(&mut mk);
+
+        let wiped = serde_json::to_value(&mk.private).unwrap();
+        assert_ne!(wiped, x2);
+        let wiped_x2 = BigInt::from_hex(wiped["x2"].as_str().unwrap()).unwrap();
+        assert_eq!(wiped_x2, BigInt::from(0));
+        assert!(!format!("{:?}", private_share).contains(x2["x2"].as_str().unwrap()));
+    }
+}
@@ -0,0 +1,426 @@
+// Handle based FFI: the host app builds a `TssSession` once and passes the pointer to
+// every call, instead of handing over endpoint, token, private share and derivation
+// map as JSON strings each time.
+
+use std::collections::HashMap;
+use std::os::raw::c_char;
+
+use curv::BigInt;
+use kms::ecdsa::two_party::MasterKey2;
+
+use crate::btc::raw_tx::create_raw_tx;
+use crate::btc::utils::{to_bitcoin_address, BTC_TESTNET};
+use crate::dto::ecdsa::{MKPosDto, PosAddressDto, PrivateShare};
+use crate::ecdsa::presign::{sign_with_pool, PresignaturePool};
+use crate::eth::raw_tx::sign_and_send;
+use crate::eth::utils::pubkey_to_eth_address;
+use crate::utilities::derive_new_key;
+use crate::utilities::err_handling::{error_to_c_string, ok_to_c_string, ErrorFFIKind};
+use crate::utilities::ffi::ffi_utils::{
+    ffi_guard, get_addresses_derivation_map_from_raw, get_client_shim_from_raw,
+    get_private_share_from_raw, get_str_from_c_char,
+};
+use crate::utilities::requests::ClientShim;
+
+pub struct TssSession {
+    client_shim: ClientShim,
+    private_share: PrivateShare,
+    last_derived_pos: u32,
+    addresses_derivation_map: HashMap<String, MKPosDto>,
+    presignatures: PresignaturePool,
+}
+
+/// What a session adds to the wallet state, in the form `tss_session_new` takes it back.
+#[derive(Serialize)]
+pub struct SessionState<'a> {
+    pub last_derived_pos: u32,
+    pub addresses_derivation_map: &'a HashMap<String, MKPosDto>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionBtcRawTxResp {
+    pub raw_tx_hex: String,
+    pub change_address: PosAddressDto,
+}
+
+impl TssSession {
+    pub fn new(
+        client_shim: ClientShim,
+        private_share: PrivateShare,
+        last_derived_pos: u32,
+        addresses_derivation_map: HashMap<String, MKPosDto>,
+    ) -> TssSession {
+        TssSession {
+            client_shim,
+            private_share,
+            last_derived_pos,
+            addresses_derivation_map,
+            presignatures: PresignaturePool::new(),
+        }
+    }
+
+    // Record a derived address so later transactions can spend from it
+    fn add_derived(&mut self, address: String, pos: u32, mk: MasterKey2) -> PosAddressDto {
+        self.last_derived_pos = pos;
+        self.addresses_derivation_map
+            .insert(address.clone(), MKPosDto { pos, mk });
+        PosAddressDto { address, pos }
+    }
+
+    pub fn state(&self) -> SessionState<'_> {
+        SessionState {
+            last_derived_pos: self.last_derived_pos,
+            addresses_derivation_map: &self.addresses_derivation_map,
+        }
+    }
+}
+
+fn session_from_raw<'a>(session: *mut TssSession) -> Result<&'a mut TssSession, ErrorFFIKind> {
+    if session.is_null() {
+        return Err(ErrorFFIKind::E105 {
+            msg: "session".to_owned(),
+        });
+    }
+    Ok(unsafe { &mut *session })
+}
+
+/// Create a session and store its handle in `out_session`. A null
+/// `c_addresses_derivation_map` starts with an empty map.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_new(
+    c_endpoint: *const c_char,
+    c_auth_token: *const c_char,
+    c_user_id: *const c_char,
+    c_private_share_json: *const c_char,
+    c_last_derived_pos: u32,
+    c_addresses_derivation_map: *const c_char,
+    out_session: *mut *mut TssSession,
+) -> *mut c_char {
+    ffi_guard("tss_session_new", || {
+        if out_session.is_null() {
+            return error_to_c_string(ErrorFFIKind::E105 {
+                msg: "out_session".to_owned(),
+            });
+        }
+
+        let client_shim = match get_client_shim_from_raw(c_endpoint, c_auth_token, c_user_id) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let private_share = match get_private_share_from_raw(c_private_share_json) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let addresses_derivation_map = if c_addresses_derivation_map.is_null() {
+            HashMap::new()
+        } else {
+            match get_addresses_derivation_map_from_raw(c_addresses_derivation_map) {
+                Ok(s) => s,
+                Err(e) => return error_to_c_string(e),
+            }
+        };
+
+        let session = TssSession::new(
+            client_shim,
+            private_share,
+            c_last_derived_pos,
+            addresses_derivation_map,
+        );
+        unsafe { *out_session = Box::into_raw(Box::new(session)) };
+
+        ok_to_c_string(&true, "session")
+    })
+}
+
+/// Release the session. A null handle is accepted. Export the state first with
+/// `tss_session_export_state`: addresses derived through the handle are lost with it.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_free(session: *mut TssSession) -> *mut c_char {
+    ffi_guard("tss_session_free", || {
+        if !session.is_null() {
+            unsafe { drop(Box::from_raw(session)) };
+        }
+        ok_to_c_string(&true, "session")
+    })
+}
+
+/// Return `{ "last_derived_pos", "addresses_derivation_map" }` of the session, to be stored
+/// by the host app and passed to the next `tss_session_new`. The map holds the derived
+/// child keys.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_export_state(session: *mut TssSession) -> *mut c_char {
+    ffi_guard("tss_session_export_state", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        ok_to_c_string(&session.state(), "session_state")
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_sign_message(
+    session: *mut TssSession,
+    c_message_le_hex: *const c_char,
+    c_x_pos: i32,
+    c_y_pos: i32,
+) -> *mut c_char {
+    ffi_guard("tss_session_sign_message", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let message_hex = match get_str_from_c_char(c_message_le_hex, "message_hex") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let message: BigInt = match serde_json::from_str(&message_hex) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::E104 {
+                    msg: "sign_message".to_owned(),
+                    e: e.to_string(),
+                })
+            }
+        };
+
+        let x: BigInt = BigInt::from(c_x_pos);
+        let y: BigInt = BigInt::from(c_y_pos);
+        let mk_child = session
+            .private_share
+            .master_key
+            .get_child(vec![x.clone(), y.clone()]);
+
+        match sign_with_pool(
+            &session.client_shim,
+            &mut session.presignatures,
+            &session.private_share,
+            message,
+            &mk_child,
+            x,
+            y,
+        ) {
+            Ok(sig) => ok_to_c_string(&sig, "signature_json"),
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
+                msg: "sig".to_owned(),
+                e,
+            }),
+        }
+    })
+}
+
+/// Precompute `c_count` presignatures so the next signatures of this session take a
+/// single round trip. Returns the pool size. The pool lives as long as the session.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_presign(session: *mut TssSession, c_count: u32) -> *mut c_char {
+    ffi_guard("tss_session_presign", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        match session.presignatures.refill(
+            &session.client_shim,
+            &session.private_share,
+            c_count as usize,
+        ) {
+            Ok(len) => ok_to_c_string(&len, "presignatures"),
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
+                msg: "presignatures".to_owned(),
+                e,
+            }),
+        }
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_get_btc_addr(session: *mut TssSession) -> *mut c_char {
+    ffi_guard("tss_session_get_btc_addr", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let (pos, mk) = derive_new_key(&session.private_share, session.last_derived_pos);
+        let address = match to_bitcoin_address(BTC_TESTNET, &mk) {
+            Ok(s) => s,
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "bitcoin_address".to_owned(),
+                    e,
+                })
+            }
+        };
+
+        let pos_address = session.add_derived(address.to_string(), pos, mk);
+        ok_to_c_string(&pos_address, "pos_address")
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_get_eth_addr(session: *mut TssSession) -> *mut c_char {
+    ffi_guard("tss_session_get_eth_addr", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let (pos, mk) = derive_new_key(&session.private_share, session.last_derived_pos);
+        let address = pubkey_to_eth_address(&mk);
+
+        let pos_address = session.add_derived(format!("{:?}", address), pos, mk);
+        ok_to_c_string(&pos_address, "pos_address")
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_get_raw_btc_tx(
+    session: *mut TssSession,
+    c_to_address: *const c_char,
+    c_amount_btc: f64,
+) -> *mut c_char {
+    ffi_guard("tss_session_get_raw_btc_tx", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let to_address = match get_str_from_c_char(c_to_address, "to_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let raw_tx = match create_raw_tx(
+            &to_address,
+            c_amount_btc,
+            &session.client_shim,
+            session.last_derived_pos,
+            &session.private_share,
+            &session.addresses_derivation_map,
+        ) {
+            Ok(Some(s)) => s,
+            Ok(None) => return ok_to_c_string(&None::<SessionBtcRawTxResp>, "raw_tx"),
+            Err(e) => {
+                return error_to_c_string(ErrorFFIKind::Tss {
+                    msg: "raw_tx".to_owned(),
+                    e,
+                })
+            }
+        };
+
+        let (address, pos, mk) = raw_tx.change_address_payload.into_parts();
+        let change_address = session.add_derived(address, pos, mk);
+
+        let resp = SessionBtcRawTxResp {
+            raw_tx_hex: raw_tx.raw_tx_hex,
+            change_address,
+        };
+        ok_to_c_string(&resp, "raw_tx")
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn tss_session_send_eth_tx(
+    session: *mut TssSession,
+    c_from_address: *const c_char,
+    c_to_address: *const c_char,
+    c_amount_eth: f64,
+) -> *mut c_char {
+    ffi_guard("tss_session_send_eth_tx", || {
+        let session = match session_from_raw(session) {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let from_address = match get_str_from_c_char(c_from_address, "from_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        let to_address = match get_str_from_c_char(c_to_address, "to_address") {
+            Ok(s) => s,
+            Err(e) => return error_to_c_string(e),
+        };
+
+        match sign_and_send(
+            &from_address,
+            &to_address,
+            c_amount_eth,
+            &session.client_shim,
+            &session.private_share,
+            &session.addresses_derivation_map,
+            &mut session.presignatures,
+        ) {
+            Ok(tx_hash) => ok_to_c_string(&tx_hash, "tx_hash"),
+            Err(e) => error_to_c_string(ErrorFFIKind::Tss {
+                msg: "tx_hash".to_owned(),
+                e,
+            }),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+
+    use super::{tss_session_export_state, tss_session_free, tss_session_get_eth_addr, TssSession};
+    use crate::dto::ecdsa::{MKPosDto, PosAddressDto};
+    use crate::tests::common::{get_test_private_share, PRIVATE_SHARE_FILENAME};
+    use crate::utilities::err_handling::FFIResponse;
+    use crate::utilities::requests::ClientShim;
+
+    fn take_ok<T: serde::de::DeserializeOwned>(ptr: *mut c_char) -> T {
+        let s = unsafe { CString::from_raw(ptr) }.into_string().unwrap();
+        let resp: FFIResponse<T> = serde_json::from_str(&s).unwrap();
+        resp.ok.unwrap()
+    }
+
+    #[derive(Deserialize)]
+    struct State {
+        last_derived_pos: u32,
+        addresses_derivation_map: HashMap<String, MKPosDto>,
+    }
+
+    #[test]
+    fn test_session_export_state() {
+        let client_shim = ClientShim::new("http://127.0.0.1:1".to_owned(), None, "user".to_owned());
+        let private_share = get_test_private_share(PRIVATE_SHARE_FILENAME);
+        let session = Box::into_raw(Box::new(TssSession::new(
+            client_shim,
+            private_share,
+            0,
+            HashMap::new(),
+        )));
+
+        let first: PosAddressDto = take_ok(tss_session_get_eth_addr(session));
+        let second: PosAddressDto = take_ok(tss_session_get_eth_addr(session));
+
+        let state: State = take_ok(tss_session_export_state(session));
+        assert_eq!(state.last_derived_pos, second.pos);
+        assert_eq!(state.addresses_derivation_map.len(), 2);
+        assert_eq!(
+            state.addresses_derivation_map[&first.address].pos,
+            first.pos
+        );
+
+        assert!(take_ok::<bool>(tss_session_free(session)));
+        assert!(take_ok::<bool>(tss_session_free(std::ptr::null_mut())));
+    }
+}
@@ -0,0 +1,170 @@
+// Crash-safe file persistence: a file is replaced only by a fully written and synced copy,
+// previous versions are kept as `<path>.1` (newest) to `<path>.<n>`, and a lock file keeps
+// two processes from mutating the same file. Files written here are readable by the owner
+// only.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use fs2::FileExt;
+
+use crate::utilities::err_handling::TssError;
+
+pub fn version_path(path: &str, version: usize) -> String {
+    format!("{}.{}", path, version)
+}
+
+/// Replace `path` with `data`: write and fsync a temporary file, shift the previous
+/// versions, then rename over `path`. At any point `path` holds either the old or the new
+/// content.
+pub fn write_atomic(path: &str, data: &[u8], keep_versions: usize) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut tmp = create_private(&tmp_path)?;
+    tmp.write_all(data)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    if keep_versions > 0 && Path::new(path).exists() {
+        for version in (1..keep_versions).rev() {
+            let older = version_path(path, version);
+            if Path::new(&older).exists() {
+                fs::rename(&older, version_path(path, version + 1))?;
+            }
+        }
+        // Copy rather than move, so `path` never goes missing
+        let mut version = create_private(&version_path(path, 1))?;
+        io::copy(&mut File::open(path)?, &mut version)?;
+        version.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path);
+    Ok(())
+}
+
+/// Delete the versions kept of `path`, once they hold secrets the current file must not
+/// leave behind (an older passphrase or none, shares replaced by a rotation).
+pub fn remove_versions(path: &str, keep_versions: usize) -> io::Result<()> {
+    for version in 1..=keep_versions {
+        if let Err(e) = fs::remove_file(version_path(path, version)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+    }
+    sync_parent_dir(path);
+    Ok(())
+}
+
+// Create or truncate `path` with mode 0600. The mode is also reset on a file left over
+// with other permissions.
+fn create_private(path: &str) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    Ok(file)
+}
+
+// Make the rename itself durable. Not possible on every platform, hence best effort.
+fn sync_parent_dir(path: &str) {
+    let parent = match Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
+/// Exclusive lock on `<path>.lock`, released when dropped.
+pub struct FileLock {
+    file: File,
+}
+
+impl FileLock {
+    /// Take the lock without waiting; fails if another process holds it.
+    pub fn try_lock(path: &str) -> Result<FileLock, TssError> {
+        let lock_path = format!("{}.lock", path);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        if file.try_lock_exclusive().is_err() {
+            return Err(TssError::Storage(format!(
+                "{} is in use by another process",
+                path
+            )));
+        }
+        Ok(FileLock { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{remove_versions, version_path, write_atomic, FileLock};
+    use std::fs;
+
+    #[test]
+    fn test_write_atomic_keeps_versions() {
+        let path = std::env::temp_dir().join("tss_storage_test.json");
+        let path = path.to_str().unwrap();
+        for i in 1..=4 {
+            write_atomic(path, format!("v{}", i).as_bytes(), 2).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(path).unwrap(), "v4");
+        assert_eq!(fs::read_to_string(version_path(path, 1)).unwrap(), "v3");
+        assert_eq!(fs::read_to_string(version_path(path, 2)).unwrap(), "v2");
+        assert!(fs::metadata(version_path(path, 3)).is_err());
+
+        remove_versions(path, 2).unwrap();
+        assert_eq!(fs::read_to_string(path).unwrap(), "v4");
+        assert!(fs::metadata(version_path(path, 1)).is_err());
+        assert!(fs::metadata(version_path(path, 2)).is_err());
+        let _ = fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join("tss_storage_mode_test.json");
+        let path = path.to_str().unwrap();
+        // A file created with the umask default, as before
+        fs::write(path, "v1").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+        write_atomic(path, b"v2", 1).unwrap();
+
+        for p in [path.to_owned(), version_path(path, 1)] {
+            let mode = fs::metadata(&p).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let _ = fs::remove_file(p);
+        }
+    }
+
+    #[test]
+    fn test_file_lock_is_exclusive() {
+        let path = std::env::temp_dir().join("tss_lock_test.json");
+        let path = path.to_str().unwrap();
+
+        let lock = FileLock::try_lock(path).unwrap();
+        assert_eq!(FileLock::try_lock(path).err().unwrap().code(), 207);
+        drop(lock);
+        assert!(FileLock::try_lock(path).is_ok());
+    }
+}
@@ -0,0 +1,96 @@
+use async_trait::async_trait;
+use serde;
+
+use super::a_requests::{self, AsyncClientShim};
+use super::err_handling::RequestError;
+use super::requests::{self, ClientShim};
+
+/// Channel used by the protocol code to exchange messages with Party 1 (the TSS server).
+///
+/// `ClientShim` is the default HTTP implementation; anything else (an in-memory channel,
+/// a recording proxy, a custom HTTP stack) only has to deliver `body` to `path` and
+/// hand back Party 1's JSON reply.
+pub trait Party1Transport {
+    fn postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize,
+        V: serde::de::DeserializeOwned;
+
+    fn post<V>(&self, path: &str) -> Result<Option<V>, RequestError>
+    where
+        V: serde::de::DeserializeOwned,
+    {
+        self.postb(path, "{}")
+    }
+
+    /// Send a step the server can safely apply twice; transports with a retry policy
+    /// may resend it after a timeout.
+    fn postb_idempotent<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize,
+        V: serde::de::DeserializeOwned,
+    {
+        self.postb(path, body)
+    }
+}
+
+/// Async flavor of `Party1Transport`, implemented by `AsyncClientShim`.
+#[async_trait]
+pub trait AsyncParty1Transport {
+    async fn a_postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize + Send,
+        V: serde::de::DeserializeOwned;
+
+    async fn a_post<V>(&self, path: &str) -> Result<Option<V>, RequestError>
+    where
+        V: serde::de::DeserializeOwned,
+    {
+        self.a_postb(path, "{}").await
+    }
+
+    async fn a_postb_idempotent<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize + Send,
+        V: serde::de::DeserializeOwned,
+    {
+        self.a_postb(path, body).await
+    }
+}
+
+impl Party1Transport for ClientShim {
+    fn postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize,
+        V: serde::de::DeserializeOwned,
+    {
+        requests::postb(self, path, body)
+    }
+
+    fn postb_idempotent<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize,
+        V: serde::de::DeserializeOwned,
+    {
+        requests::postb_idempotent(self, path, body)
+    }
+}
+
+#[async_trait]
+impl AsyncParty1Transport for AsyncClientShim {
+    async fn a_postb<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize + Send,
+        V: serde::de::DeserializeOwned,
+    {
+        a_requests::a_postb(self, path, body).await
+    }
+
+    async fn a_postb_idempotent<T, V>(&self, path: &str, body: T) -> Result<Option<V>, RequestError>
+    where
+        T: serde::ser::Serialize + Send,
+        V: serde::de::DeserializeOwned,
+    {
+        a_requests::a_postb_idempotent(self, path, body).await
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::Result;
 use bitcoin::{self};
 use curv::elliptic::curves::secp256_k1::GE;
 use curv::elliptic::curves::traits::ECPoint;
@@ -8,32 +7,36 @@ use kms::ecdsa::two_party::*;
 use serde_json::{self, Value};
 use std::fs;
 use web3::types::H256;
-
-use centipede::juggling::proof_system::{Helgamalsegmented, Proof};
-use centipede::juggling::segmentation::Msegmentation;
+use zeroize::{Zeroize, Zeroizing};
 
 use crate::btc::raw_tx::select_tx_in;
 use crate::btc::utils::{get_bitcoin_network, to_bitcoin_address, to_bitcoin_public_key};
+use crate::dto::backup::ClientBackup;
 use crate::dto::btc::BlockCypherRawTx;
 use crate::dto::ecdsa::{MKPosDto, PrivateShare};
+use crate::ecdsa::presign::{PresignaturePool, PresignatureStore};
 use crate::ecdsa::recover::{backup_client_mk, verify_client_backup};
 use crate::eth;
 use crate::eth::raw_tx::sign_and_send;
 use crate::eth::utils::pubkey_to_eth_address;
 use crate::tests::common::RINKEBY_TEST_API;
 use crate::utilities::derive_new_key;
-use crate::utilities::requests::ClientShim;
+use crate::utilities::encryption::{self, FileKey};
+use crate::utilities::err_handling::TssError;
+use crate::utilities::storage::{self, FileLock};
+use crate::utilities::transport::Party1Transport;
 
 use super::btc;
 
 use super::ecdsa;
 use super::escrow;
-use super::utilities::requests;
 use std::collections::HashMap;
 
 // TODO: move that to a config file and double check electrum server addresses
 pub const WALLET_FILENAME: &str = "wallet/wallet.json";
 const BACKUP_FILENAME: &str = "wallet/backup.data";
+// Previous wallet files kept as wallet.json.1 (newest) .. wallet.json.3
+const WALLET_VERSIONS: usize = 3;
 const BLOCK_CYPHER_HOST: &str = "https://api.blockcypher.com/v1/btc/test3";
 #[derive(Serialize, Deserialize)]
 pub struct Wallet {
@@ -43,10 +46,18 @@ pub struct Wallet {
     pub private_share: PrivateShare,
     pub last_derived_pos: u32,
     pub addresses_derivation_map: HashMap<String, MKPosDto>,
+    #[serde(default)]
+    pub presignatures: PresignaturePool,
+    /// Rotated share waiting for P1's confirmation, see `finish_rotation`.
+    #[serde(default)]
+    pub pending_rotation: Option<PrivateShare>,
+    /// Key the wallet file is encrypted with; `None` saves plaintext JSON.
+    #[serde(skip)]
+    file_key: Option<FileKey>,
 }
 
 impl Wallet {
-    pub fn new(client_shim: &ClientShim, net: &str, c_type: &str) -> Wallet {
+    pub fn new<C: Party1Transport>(client_shim: &C, net: &str, c_type: &str) -> Wallet {
         // let id = Uuid::new_v4().to_string();
         let private_share = match ecdsa::get_private_share(client_shim) {
             Ok(p) => p,
@@ -63,65 +74,127 @@ impl Wallet {
             private_share,
             last_derived_pos,
             addresses_derivation_map,
+            presignatures: PresignaturePool::new(),
+            pending_rotation: None,
+            file_key: None,
         }
     }
 
-    pub fn rotate(self, client_shim: &ClientShim, filepath: &str) {
+    /// Rotate the private share in two phases: the rotated share is saved as pending
+    /// before P1 is asked to switch to it, and only replaces the current share once P1
+    /// confirmed. A rotation interrupted in between is finished by `finish_rotation`.
+    pub fn rotate<C: Party1Transport>(
+        &mut self,
+        client_shim: &C,
+        filepath: &str,
+    ) -> Result<(), TssError> {
+        self.finish_rotation(client_shim, filepath)?;
+
         let rotated_private_share =
-            ecdsa::rotate_private_share(self.private_share, client_shim).unwrap();
-        let addresses_derivation_map = HashMap::new();
-        let mut wallet_after_rotate = Wallet {
-            id: self.id.clone(),
-            coin_type: self.coin_type.clone(),
-            network: self.network.clone(),
-            private_share: rotated_private_share,
-            last_derived_pos: self.last_derived_pos,
-            addresses_derivation_map,
+            ecdsa::rotate::begin_rotation(&self.private_share, client_shim)?;
+        self.pending_rotation = Some(rotated_private_share);
+        self.save_to(filepath);
+
+        self.finish_rotation(client_shim, filepath)?;
+        Ok(())
+    }
+
+    /// Complete a pending rotation, e.g. one interrupted by a crash; call it after loading
+    /// the wallet. Returns whether the share was replaced.
+    ///
+    /// If P1 answers that it does not know the pending rotation, it never switched: the
+    /// pending share is dropped and the current one kept. On any other error the pending
+    /// share is kept for the next attempt.
+    pub fn finish_rotation<C: Party1Transport>(
+        &mut self,
+        client_shim: &C,
+        filepath: &str,
+    ) -> Result<bool, TssError> {
+        let rotated_private_share = match self.pending_rotation.take() {
+            Some(p) => p,
+            None => return Ok(false),
         };
-        wallet_after_rotate.derived().unwrap();
 
-        wallet_after_rotate.save_to(filepath);
+        if rotated_private_share.master_key.public.q != self.private_share.master_key.public.q {
+            self.save_to(filepath);
+            return Err(TssError::verification(
+                "rotation",
+                "rotated share has a different joint public key",
+            ));
+        }
+
+        match ecdsa::rotate::confirm_rotation(client_shim, &rotated_private_share) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.save_to(filepath);
+                return Err(TssError::protocol(
+                    "rotation confirm",
+                    "P1 does not know the pending rotation",
+                ));
+            }
+            Err(e) => {
+                self.pending_rotation = Some(rotated_private_share);
+                return Err(e);
+            }
+        }
+
+        self.private_share = rotated_private_share;
+        self.addresses_derivation_map = HashMap::new();
+        // Presignatures of the old key share are useless after rotation
+        self.presignatures = PresignaturePool::new();
+        self.derived()?;
+        self.save_to(filepath);
+        // Older versions still hold the share P1 no longer accepts
+        storage::remove_versions(filepath, WALLET_VERSIONS)?;
+
+        debug!("(wallet id: {}) Rotation confirmed", self.id);
+        Ok(true)
     }
 
-    pub fn backup(&self) {
-        let client_backup_json = backup_client_mk(&self.private_share).unwrap();
+    /// Write the backup of the private share, encrypted to the escrow public key `y`.
+    pub fn backup(&self, y: &GE) {
+        let client_backup_json = backup_client_mk(
+            &self.private_share,
+            y,
+            Some(&self.coin_type),
+            Some(&self.network),
+        )
+        .unwrap();
         fs::write(BACKUP_FILENAME, client_backup_json).expect("Unable to save client backup!");
 
         debug!("(wallet id: {}) Backup wallet with escrow", &self.id);
     }
 
-    pub fn verify_backup(&self, escrow_service: escrow::Escrow) {
-        let y = escrow_service.get_public_key();
+    pub fn verify_backup(&self, y: &GE) {
         let data = fs::read_to_string(BACKUP_FILENAME).expect("Unable to load client backup!");
-        match verify_client_backup(y, &data) {
+        match verify_client_backup(*y, &data) {
             Ok(_x) => println!("backup verified 🍻"),
             Err(_e) => println!("Backup was not verified correctly 😲"),
         }
     }
 
-    pub fn recover_and_save_share(
-        escrow_service: escrow::Escrow,
+    /// Decrypt the backup with the escrow secret, or with `t` custodians' partial
+    /// decryptions for a threshold escrow, and save the recovered wallet encrypted under
+    /// `passphrase` (`None` stores it as plaintext).
+    pub fn recover_and_save_share<C: Party1Transport>(
+        recovery_key: escrow::RecoveryKey,
         net: &str,
-        client_shim: &ClientShim,
+        client_shim: &C,
+        passphrase: Option<&str>,
     ) -> Wallet {
-        let g: GE = ECPoint::generator();
-        let y_priv = escrow_service.get_private_key();
-
-        let data = fs::read_to_string(BACKUP_FILENAME).expect("Unable to load client backup!");
-
-        let (encryptions, _proof, public_data, chain_code2, key_id): (
-            Helgamalsegmented,
-            Proof,
-            Party2Public,
-            BigInt,
-            String,
-        ) = serde_json::from_str(&data).unwrap();
+        let client_backup = Wallet::load_backup().unwrap();
+        let key_id = client_backup.id;
 
-        let sk = Msegmentation::decrypt(&encryptions, &g, &y_priv, &escrow::SEGMENT_SIZE);
+        let mut sk = recovery_key
+            .decrypt_backup(&client_backup)
+            .expect("Unable to decrypt client backup!");
+        drop(recovery_key);
 
         let client_master_key_recovered =
-            MasterKey2::recover_master_key(sk.unwrap(), public_data, chain_code2);
-        let pos_old: u32 = requests::post(client_shim, &format!("ecdsa/{}/recover", key_id))
+            MasterKey2::recover_master_key(sk, client_backup.public, client_backup.chain_code);
+        sk.zeroize();
+        let pos_old: u32 = client_shim
+            .postb_idempotent(&format!("ecdsa/{}/recover", key_id), "{}")
             .unwrap()
             .unwrap();
 
@@ -131,56 +204,174 @@ impl Wallet {
         // let id = Uuid::new_v4().to_string();
         let addresses_derivation_map = HashMap::new(); //TODO: add a fucntion to recreate
 
-        let new_wallet = Wallet {
+        let mut new_wallet = Wallet {
             id: key_id.clone(),
-            coin_type: "btc".to_owned(),
-            network: net.to_owned(),
+            // Backups from before versioning did not record them
+            coin_type: client_backup.coin_type.unwrap_or_else(|| "btc".to_owned()),
+            network: client_backup.network.unwrap_or_else(|| net.to_owned()),
             private_share: PrivateShare {
                 master_key: client_master_key_recovered,
                 id: key_id,
             },
             last_derived_pos: pos_old,
             addresses_derivation_map,
+            presignatures: PresignaturePool::new(),
+            pending_rotation: None,
+            file_key: None,
         };
 
+        new_wallet
+            .set_passphrase(passphrase)
+            .expect("Unable to derive wallet key!");
         new_wallet.save();
         println!("Recovery Completed Successfully ❤️");
 
         new_wallet
     }
 
+    /// The backup written by `backup`, e.g. for custodians to decrypt their part of.
+    pub fn load_backup() -> Result<ClientBackup, TssError> {
+        ClientBackup::from_json(&fs::read_to_string(BACKUP_FILENAME)?)
+    }
+
     pub fn save_to(&self, filepath: &str) {
-        let wallet_json = serde_json::to_string(self).unwrap();
+        let wallet_json = Zeroizing::new(serde_json::to_string(self).unwrap());
+        self.write_to(filepath, &wallet_json)
+            .expect("Unable to save wallet!");
+    }
 
-        fs::write(filepath, wallet_json).expect("Unable to save wallet!");
+    // Save the wallet with `presignatures` in place of its own pool, which is lent out
+    fn save_with_presignatures(
+        &self,
+        filepath: &str,
+        presignatures: &PresignaturePool,
+    ) -> Result<(), TssError> {
+        let mut wallet_value = serde_json::to_value(self)?;
+        wallet_value["presignatures"] = serde_json::to_value(presignatures)?;
+        let wallet_json = Zeroizing::new(wallet_value.to_string());
+        self.write_to(filepath, &wallet_json)
+    }
+
+    fn write_to(&self, filepath: &str, wallet_json: &str) -> Result<(), TssError> {
+        let encrypted;
+        let data = match &self.file_key {
+            Some(key) => {
+                encrypted = key.encrypt(wallet_json.as_bytes())?;
+                encrypted.as_bytes()
+            }
+            None => wallet_json.as_bytes(),
+        };
+
+        storage::write_atomic(filepath, data, WALLET_VERSIONS)?;
 
         debug!("(wallet id: {}) Saved wallet to disk", self.id);
+        Ok(())
     }
 
     pub fn save(&self) {
         self.save_to(WALLET_FILENAME)
     }
 
+    /// Lock the wallet file at `filepath` against other processes until the guard is
+    /// dropped. Take it before loading a wallet that will be changed and saved.
+    pub fn lock(filepath: &str) -> Result<FileLock, TssError> {
+        FileLock::try_lock(filepath)
+    }
+
+    /// Encrypt the wallet file with `passphrase` from the next save on; `None` stores it as
+    /// plaintext.
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>) -> Result<(), TssError> {
+        self.file_key = match passphrase {
+            Some(p) => Some(FileKey::new(p)?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Save the wallet at `filepath` under `passphrase` and delete the kept versions, which
+    /// are still readable with the old passphrase (or without one).
+    pub fn change_passphrase(
+        &mut self,
+        passphrase: Option<&str>,
+        filepath: &str,
+    ) -> Result<(), TssError> {
+        self.set_passphrase(passphrase)?;
+        self.save_to(filepath);
+        storage::remove_versions(filepath, WALLET_VERSIONS)?;
+        Ok(())
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.file_key.is_some()
+    }
+
+    /// Load a wallet, asking for the passphrase on the terminal if the file is encrypted.
     pub fn load_from(filepath: &str) -> Wallet {
-        let data = fs::read_to_string(filepath).expect("Unable to load wallet!");
+        let data = Zeroizing::new(fs::read_to_string(filepath).expect("Unable to load wallet!"));
 
-        let wallet: Wallet = serde_json::from_str(&data).unwrap();
+        let wallet = if encryption::is_encrypted(&data) {
+            let passphrase = Zeroizing::new(
+                rpassword::prompt_password("Wallet passphrase: ")
+                    .expect("Unable to read passphrase!"),
+            );
+            Wallet::from_file_data(&data, Some(&passphrase)).unwrap()
+        } else {
+            Wallet::from_file_data(&data, None).unwrap()
+        };
 
         debug!("(wallet id: {}) Loaded wallet to memory", wallet.id);
 
         wallet
     }
 
+    /// Load a wallet without prompting; `passphrase` is required for an encrypted file.
+    pub fn load_from_with_passphrase(
+        filepath: &str,
+        passphrase: Option<&str>,
+    ) -> Result<Wallet, TssError> {
+        let data = Zeroizing::new(fs::read_to_string(filepath)?);
+        Wallet::from_file_data(&data, passphrase)
+    }
+
+    fn from_file_data(data: &str, passphrase: Option<&str>) -> Result<Wallet, TssError> {
+        if !encryption::is_encrypted(data) {
+            let wallet: Wallet = serde_json::from_str(data)?;
+            if !wallet.presignatures.is_empty() {
+                warn!(
+                    "(wallet id: {}) unencrypted wallet file holds presignature secrets",
+                    wallet.id
+                );
+            }
+            return Ok(wallet);
+        }
+
+        let passphrase = match passphrase {
+            Some(p) => p,
+            None => {
+                return Err(TssError::Storage(
+                    "wallet file is encrypted, passphrase required".to_owned(),
+                ))
+            }
+        };
+        let (file_key, wallet_json) = encryption::decrypt(data, passphrase)?;
+        let mut wallet: Wallet = serde_json::from_slice(&wallet_json)?;
+        wallet.file_key = Some(file_key);
+        Ok(wallet)
+    }
+
     pub fn load() -> Wallet {
         Wallet::load_from(WALLET_FILENAME)
     }
 
-    pub fn send(
+    /// Send `amount` from `from_address`. The wallet file at `filepath`, the one this wallet
+    /// was loaded from, is saved whenever a presignature is taken.
+    pub fn send<C: Party1Transport>(
         &mut self,
         from_address: &str,
         to_address: &str,
         amount: f64,
-        client_shim: &ClientShim,
+        client_shim: &C,
+        filepath: &str,
     ) -> String {
         let coin_type = &self.coin_type;
         if coin_type == "btc" {
@@ -201,13 +392,14 @@ impl Wallet {
             };
 
             let raw_tx = raw_tx_opt.unwrap();
-            let change_address_payload = raw_tx.change_address_payload;
+            let (change_address, change_pos, change_mk) =
+                raw_tx.change_address_payload.into_parts();
 
             let _ = &self.addresses_derivation_map.insert(
-                change_address_payload.address,
+                change_address,
                 MKPosDto {
-                    mk: change_address_payload.mk,
-                    pos: change_address_payload.pos,
+                    mk: change_mk,
+                    pos: change_pos,
                 },
             );
             self.last_derived_pos = &self.last_derived_pos + 1;
@@ -243,15 +435,22 @@ impl Wallet {
             };
             return tx_hash.to_owned();
         } else if coin_type == "eth" {
-            let tx_hash = send_eth(
+            let mut presignatures = WalletPresignatures {
+                pool: std::mem::take(&mut self.presignatures),
+                wallet: &*self,
+                filepath,
+            };
+            let result = send_eth(
                 amount,
                 client_shim,
                 from_address,
                 to_address,
                 &self.private_share,
                 &self.addresses_derivation_map,
-            )
-            .unwrap();
+                &mut presignatures,
+            );
+            self.presignatures = presignatures.pool;
+            let tx_hash = result.unwrap();
 
             println!(
                 "Sent {} ETH to address {}. Transaction State: {:?}",
@@ -262,6 +461,19 @@ impl Wallet {
         "".to_owned()
     }
 
+    /// Top the presignature pool up to `count`, so that the next `count` ETH payments need
+    /// a single signing round trip. The pool is saved with the wallet, in plaintext unless
+    /// the wallet has a passphrase.
+    pub fn presign<C: Party1Transport>(
+        &mut self,
+        client_shim: &C,
+        count: usize,
+    ) -> Result<usize, TssError> {
+        let missing = count.saturating_sub(self.presignatures.len());
+        self.presignatures
+            .refill(client_shim, &self.private_share, missing)
+    }
+
     pub fn get_crypto_address(&mut self) -> String {
         let (pos, mk) = derive_new_key(&self.private_share, self.last_derived_pos);
         let coin_type = &self.coin_type;
@@ -285,7 +497,7 @@ impl Wallet {
         "".to_owned()
     }
 
-    pub fn derived(&mut self) -> Result<()> {
+    pub fn derived(&mut self) -> Result<(), TssError> {
         if self.coin_type == "btc" {
             for i in 0..self.last_derived_pos {
                 let (pos, mk) = derive_new_key(&self.private_share, i);
@@ -336,7 +548,10 @@ impl Wallet {
 }
 
 #[tokio::main]
-async fn get_eth_balance(last_derived_pos: u32, private_share: &PrivateShare) -> Result<f64> {
+async fn get_eth_balance(
+    last_derived_pos: u32,
+    private_share: &PrivateShare,
+) -> Result<f64, TssError> {
     let balance_l =
         eth::utils::get_all_addresses_balance(RINKEBY_TEST_API, last_derived_pos, private_share)
             .await?;
@@ -349,14 +564,34 @@ async fn get_eth_balance(last_derived_pos: u32, private_share: &PrivateShare) ->
     Ok(total)
 }
 
-fn send_eth(
+// The wallet's pool while a payment draws from it: the wallet file is saved with the
+// shortened pool before a presignature is used
+struct WalletPresignatures<'a> {
+    pool: PresignaturePool,
+    wallet: &'a Wallet,
+    filepath: &'a str,
+}
+
+impl PresignatureStore for WalletPresignatures<'_> {
+    fn pool(&mut self) -> &mut PresignaturePool {
+        &mut self.pool
+    }
+
+    fn persist(&mut self) -> Result<(), TssError> {
+        self.wallet
+            .save_with_presignatures(self.filepath, &self.pool)
+    }
+}
+
+fn send_eth<C: Party1Transport, S: PresignatureStore>(
     eth_value: f64,
-    client_shim: &ClientShim,
+    client_shim: &C,
     from: &str,
     to: &str,
     private_share: &PrivateShare,
     addresses_derivation_map: &HashMap<String, MKPosDto>,
-) -> Result<H256> {
+    presignatures: &mut S,
+) -> Result<H256, TssError> {
     let result = sign_and_send(
         from,
         to,
@@ -364,6 +599,7 @@ fn send_eth(
         client_shim,
         private_share,
         addresses_derivation_map,
+        presignatures,
     )?;
     Ok(result)
 }